@@ -0,0 +1,81 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use labeler::config::Config;
+use labeler::matcher::TitleMatch;
+use labeler::{CliConf, FilterOp, FilterType};
+use std::fmt::Write;
+
+const CLUSTERS: usize = 2_000;
+const TIDBS: usize = 50;
+const RULES: usize = 1_000;
+const LABELS_PER_CLUSTER: usize = 25;
+
+/// Write a dataset whose labels file has `TIDBS * RULES` patterns, and load it.
+fn title_match() -> TitleMatch {
+    let dir = std::env::temp_dir().join(format!("labeler-bench-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("tidb")).expect("writable temp dir");
+
+    let mut events = String::new();
+    let mut clusters = Vec::new();
+    let mut labeled = Vec::new();
+    for cid in 1..=CLUSTERS {
+        writeln!(events, "e{},GET /index.php?id={}", cid, cid).expect("in-memory write");
+        clusters.push(format!(
+            r#"{{"cluster_id":{},"cluster_size":1,"signature":null,"score":0.5,"events":["e{}"]}}"#,
+            cid, cid
+        ));
+        let labels: Vec<String> = (0..LABELS_PER_CLUSTER)
+            .map(|i| {
+                let pattern = (cid * LABELS_PER_CLUSTER + i) % (TIDBS * RULES);
+                format!("[{},{},1.0]", pattern / RULES + 1, pattern % RULES + 1)
+            })
+            .collect();
+        labeled.push(format!(
+            r#"[{},[["e{}",[{}]]]]"#,
+            cid,
+            cid,
+            labels.join(",")
+        ));
+    }
+    let files = [
+        ("events.csv", events),
+        (
+            "clusters.json",
+            format!(
+                r#"{{"detector_id":1,"events_count":{},"clusters_count":{},"outlier_count":0,"clusters":[{}],"outliers":[]}}"#,
+                CLUSTERS,
+                CLUSTERS,
+                clusters.join(",")
+            ),
+        ),
+        (
+            "labels.json",
+            format!(
+                r#"{{"representative_labels":0,"event_labels":{},"representative":[],"events":[{}]}}"#,
+                CLUSTERS,
+                labeled.join(",")
+            ),
+        ),
+    ];
+    for (file, content) in &files {
+        std::fs::write(dir.join(file), content).expect("writable temp dir");
+    }
+    let cfg = Config::from_dir(&dir.display().to_string()).expect("valid dataset");
+    let tm = TitleMatch::new(&cfg, false, false).expect("valid dataset");
+    std::fs::remove_dir_all(&dir).expect("removable temp dir");
+    tm
+}
+
+fn filter_by_label(c: &mut Criterion) {
+    let tm = title_match();
+    let cfg = CliConf::default();
+    for pattern in &[None, Some("5"), Some("5:12"), Some(":12")] {
+        assert!(tm.preview(FilterType::Label, FilterOp::EQ, *pattern, &cfg) > 0);
+        let name = format!("filter label {}", pattern.unwrap_or("(any)"));
+        c.bench_function(&name, |b| {
+            b.iter(|| tm.preview(FilterType::Label, FilterOp::EQ, black_box(*pattern), &cfg))
+        });
+    }
+}
+
+criterion_group!(benches, filter_by_label);
+criterion_main!(benches);
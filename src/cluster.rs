@@ -1,17 +1,28 @@
 use crate::config::Load;
 use crate::events::Events;
 use crate::labels::Labels;
-use crate::{CliConf, ClusterId, FilterOp, FilterType, MessageId, Qualifier, Score};
+use crate::parser;
+use crate::{
+    CliConf, ClusterId, FilterOp, FilterType, MessageId, Qualifier, Score, SignatureDisplay,
+    SAMPLES_COUNT_ALL,
+};
+use ansi_term::Colour;
 use anyhow::Result;
-use log::info;
+use log::{info, warn};
 use regex::Regex;
 use serde::Deserialize;
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
+use std::io::{self, Write};
 use std::str::FromStr;
 
 const SIGNATURE_DISPLAY_LENGTH: usize = 200;
+const SIGNATURE_GROUP_PREFIX_LENGTH: usize = 40;
+const SIGNATURE_GROUP_NONE: &str = "(none)";
+const DERIVED_SIGNATURE_TOKENS: usize = 5;
 const CLUSTER_ID_FOR_OUTLIERS: ClusterId = 1_000_000;
+const ALL_SAMPLES_WARNING_COUNT: usize = 10_000;
 #[derive(Deserialize)]
 struct SavedClusters {
     detector_id: i32,
@@ -37,6 +48,7 @@ impl SavedClusters {
     fn cluster_ids(&self) -> Vec<ClusterId> {
         let mut clusters: Vec<_> = self.clusters.iter().map(|c| c.cluster_id).collect();
         clusters.sort_unstable();
+        clusters.dedup();
         clusters
     }
 
@@ -53,6 +65,12 @@ impl SavedClusters {
     }
 }
 
+/// Per-cluster state.
+///
+/// The user edits (`new_qualifier`, `tags` and the event filters `filter`/`filtered_events`) belong to
+/// the cluster, not to the filter layer. They are kept when a layer is popped by `/x` and
+/// shown again when the cluster appears in another layer. Event filters are reset only by
+/// `/event clear`.
 #[derive(Debug, Default, Clone)]
 pub struct Members {
     id: ClusterId,
@@ -60,7 +78,11 @@ pub struct Members {
     score: Score,
     qualifier: Qualifier,
     new_qualifier: Qualifier,
+    saved_qualifier: Qualifier,
+    tags: HashSet<String>,
+    tags_changed: bool, // tags are changed after the last save
     signature: Option<String>,
+    column_signature: bool, // signature is set from the signature column of events
     event_ids: Vec<MessageId>,
     filtered_events: Vec<Vec<MessageId>>, // tokens: HashMap<String, Vec<MessageId>>, // TODO: calculate token occurrences to correct label-score
     filter: Vec<String>,
@@ -68,18 +90,46 @@ pub struct Members {
 
 impl fmt::Display for Members {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, " cluster {}", self.id)?;
+        let digits = f.precision();
+        self.write_header(f, digits, crate::is_color_enabled())
+    }
+}
+
+impl Members {
+    /// Write the id, qualifier, size and score of the cluster, with `digits` decimal digits
+    /// of the score. The qualifier is in colors if `color` is set.
+    fn write_header(
+        &self,
+        f: &mut dyn fmt::Write,
+        digits: Option<usize>,
+        color: bool,
+    ) -> fmt::Result {
+        // `*` marks the cluster with event filters
+        let marker = if self.has_event_filter() { "*" } else { "" };
+        write!(f, " cluster {}{}", self.id, marker)?;
         if self.qualifier == self.new_qualifier {
-            write!(f, ", {}", self.new_qualifier)?;
+            write!(f, ", {}", self.new_qualifier.paint(color))?;
         } else {
-            write!(f, ", {}<-{}", self.new_qualifier, self.qualifier)?;
+            write!(
+                f,
+                ", {}<-{}",
+                self.new_qualifier.paint(color),
+                self.qualifier.paint(color)
+            )?;
         }
         write!(f, ", {} events", self.size)?;
-        write!(f, ", score = {}", self.score)
+        if let Some(digits) = digits {
+            write!(f, ", score = {:.*}", digits, self.score)
+        } else {
+            write!(f, ", score = {}", self.score)
+        }
+    }
+
+    #[must_use]
+    pub fn has_event_filter(&self) -> bool {
+        !self.filter.is_empty()
     }
-}
 
-impl Members {
     #[must_use]
     pub fn signature(&self) -> Option<String> {
         if let Some(s) = &self.signature {
@@ -97,6 +147,58 @@ impl Members {
         }
     }
 
+    /// Return the number of events of the cluster found in `events`.
+    #[must_use]
+    pub fn loaded_event_count(&self, events: &Events) -> usize {
+        self.event_ids
+            .iter()
+            .filter(|message_id| events.contains(message_id))
+            .count()
+    }
+
+    /// Synthesize a signature from the most common tokens of the events,
+    /// or the first event if the events have no tokens.
+    #[must_use]
+    pub fn derived_signature(&self, events: &Events) -> Option<String> {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for message_id in &self.event_ids {
+            if let Some(tokens) = events.tokens(message_id) {
+                for token in tokens {
+                    *counts.entry(token.as_str()).or_insert(0) += 1;
+                }
+            }
+        }
+        if counts.is_empty() {
+            return self.event_ids.iter().find_map(|message_id| {
+                events.get_message(message_id).map(|msg| {
+                    msg.chars()
+                        .take(SIGNATURE_DISPLAY_LENGTH)
+                        .collect::<String>()
+                })
+            });
+        }
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        Some(
+            counts
+                .iter()
+                .take(DERIVED_SIGNATURE_TOKENS)
+                .map(|(token, _)| *token)
+                .collect::<Vec<_>>()
+                .join(" "),
+        )
+    }
+
+    /// Return the events selected by the last event filter, or all events if no filter is set.
+    #[must_use]
+    pub fn active_event_ids(&self) -> &[MessageId] {
+        if let Some(last) = self.filtered_events.last() {
+            last
+        } else {
+            &self.event_ids
+        }
+    }
+
     pub fn set_qualifier(&mut self, qualifier: Qualifier) -> bool {
         if self.new_qualifier != qualifier {
             self.new_qualifier = qualifier;
@@ -104,12 +206,20 @@ impl Members {
         }
         false
     }
+
+    /// Return the tags in alphabetical order.
+    #[must_use]
+    pub fn tags(&self) -> Vec<String> {
+        let mut tags: Vec<String> = self.tags.iter().cloned().collect();
+        tags.sort_unstable();
+        tags
+    }
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct Clusters {
     clusters: Vec<ClusterId>,
-    _outliers: Vec<String>,
+    outliers: Vec<String>, // raw event lines of outliers
     clusters_map: HashMap<ClusterId, Members>,
     tokens_clusters_map: HashMap<String, Vec<ClusterId>>,
 }
@@ -118,7 +228,11 @@ impl Clusters {
     /// # Errors
     ///
     /// Will return `Err` if the query to get cluster records for the specified datasource failed.
-    pub fn new(path: &str, labels: &Labels, delimiter: char) -> Result<Self> {
+    ///
+    /// Outliers are raw event lines. Their message ids are the fields at `key_index`.
+    /// If a cluster id appears more than once, the first entry is used with a warning.
+    /// NaN scores are replaced with 0 to keep the comparisons of scores consistent.
+    pub fn new(path: &str, labels: &Labels, delimiter: char, key_index: usize) -> Result<Self> {
         let save_clusters = SavedClusters::from_path(path)?;
         {
             let (detector_id, events_count, clusters_count, outliers_count) =
@@ -129,42 +243,48 @@ impl Clusters {
             );
         }
         let mut clusters = save_clusters.cluster_ids();
-        let mut clusters_map: HashMap<ClusterId, Members> = save_clusters
-            .clusters
-            .iter()
-            .map(|m| {
-                let qualifier = if labels.is_labeled(m.cluster_id) {
-                    Qualifier::Suspicious
-                } else {
-                    Qualifier::default()
-                };
-                (
-                    m.cluster_id,
-                    Members {
-                        id: m.cluster_id,
-                        size: m.cluster_size,
-                        score: m.score.unwrap_or_default(),
-                        qualifier,
-                        new_qualifier: qualifier,
-                        signature: m.signature.as_ref().cloned(),
-                        event_ids: m.events.clone(),
-                        filtered_events: Vec::new(),
-                        filter: Vec::new(),
-                    },
-                )
-            })
-            .collect();
+        let mut clusters_map: HashMap<ClusterId, Members> = HashMap::new();
+        let mut duplicates = 0;
+        for m in &save_clusters.clusters {
+            if clusters_map.contains_key(&m.cluster_id) {
+                warn!("duplicate cluster {} in {} is skipped", m.cluster_id, path);
+                duplicates += 1;
+                continue;
+            }
+            let qualifier = if labels.is_labeled(m.cluster_id) {
+                Qualifier::Suspicious
+            } else {
+                Qualifier::default()
+            };
+            let score = valid_score(m.cluster_id, m.score);
+            clusters_map.insert(
+                m.cluster_id,
+                Members {
+                    id: m.cluster_id,
+                    size: m.cluster_size,
+                    score,
+                    qualifier,
+                    new_qualifier: qualifier,
+                    saved_qualifier: qualifier,
+                    tags: HashSet::new(),
+                    tags_changed: false,
+                    signature: m.signature.as_ref().cloned(),
+                    column_signature: false,
+                    event_ids: m.events.clone(),
+                    filtered_events: Vec::new(),
+                    filter: Vec::new(),
+                },
+            );
+        }
+        if duplicates > 0 {
+            warn!(
+                "{} duplicate clusters of {} are skipped. the first ones are used.",
+                duplicates, path
+            );
+        }
 
         if !save_clusters.outliers().is_empty() {
-            let message_id_index = 1;
-            let event_ids: Vec<_> = save_clusters
-                .outliers()
-                .iter()
-                .filter_map(|raw| {
-                    let s: Vec<_> = raw.split(delimiter).collect();
-                    s.get(message_id_index).map(|msg_id| (*msg_id).to_string())
-                })
-                .collect();
+            let event_ids = outlier_event_ids(save_clusters.outliers(), delimiter, key_index);
             clusters_map.insert(
                 CLUSTER_ID_FOR_OUTLIERS,
                 Members {
@@ -173,7 +293,11 @@ impl Clusters {
                     score: 0.0,
                     qualifier: Qualifier::default(),
                     new_qualifier: Qualifier::default(),
+                    saved_qualifier: Qualifier::default(),
+                    tags: HashSet::new(),
+                    tags_changed: false,
                     signature: None,
+                    column_signature: false,
                     event_ids,
                     filtered_events: Vec::new(),
                     filter: Vec::new(),
@@ -184,7 +308,7 @@ impl Clusters {
 
         Ok(Self {
             clusters,
-            _outliers: save_clusters.outliers,
+            outliers: save_clusters.outliers,
             clusters_map,
             tokens_clusters_map: HashMap::new(),
         })
@@ -197,6 +321,77 @@ impl Clusters {
             .collect()
     }
 
+    /// Return the message ids of all clusters as `event_ids`, but with the outliers split by
+    /// `delimiter`.
+    #[must_use]
+    pub fn event_ids_by_delimiter(&self, delimiter: char, key_index: usize) -> Vec<String> {
+        let mut event_ids: Vec<String> = self
+            .clusters_map
+            .values()
+            .filter(|c| c.id != CLUSTER_ID_FOR_OUTLIERS)
+            .flat_map(|c| c.event_ids.clone())
+            .collect();
+        event_ids.extend(outlier_event_ids(&self.outliers, delimiter, key_index));
+        event_ids
+    }
+
+    /// Split the outliers by `delimiter` again to set their message ids.
+    /// The event filters of the outliers are cleared.
+    pub fn set_delimiter(&mut self, delimiter: char, key_index: usize) {
+        let event_ids = outlier_event_ids(&self.outliers, delimiter, key_index);
+        if let Some(c) = self.clusters_map.get_mut(&CLUSTER_ID_FOR_OUTLIERS) {
+            c.event_ids = event_ids;
+            c.filtered_events.clear();
+            c.filter.clear();
+        }
+    }
+
+    /// Return the clusters none of whose events are found in `events`.
+    #[must_use]
+    pub fn unloaded_clusters(&self, events: &Events) -> Vec<ClusterId> {
+        let mut clusters: Vec<ClusterId> = self
+            .clusters_map
+            .values()
+            .filter(|c| !c.event_ids.is_empty() && c.loaded_event_count(events) == 0)
+            .map(|c| c.id)
+            .collect();
+        clusters.sort_unstable();
+        clusters
+    }
+
+    /// Return the number of distinct events referenced by clusters and outliers.
+    #[must_use]
+    pub fn referenced_events_count(&self) -> usize {
+        self.clusters_map
+            .values()
+            .flat_map(|c| c.event_ids.iter())
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    /// Set the signature of the clusters which have none to the `column` of their first
+    /// loaded event which is not empty. The signatures set from the column before are set again.
+    ///
+    /// Return the number of clusters whose signature is set.
+    pub fn init_signatures(&mut self, events: &Events, column: usize) -> usize {
+        let mut count = 0;
+        for c in self.clusters_map.values_mut() {
+            if c.signature.is_some() && !c.column_signature {
+                continue;
+            }
+            c.signature = c
+                .event_ids
+                .iter()
+                .filter_map(|message_id| events.field(message_id, column))
+                .find(|field| !field.is_empty());
+            c.column_signature = c.signature.is_some();
+            if c.column_signature {
+                count += 1;
+            }
+        }
+        count
+    }
+
     pub fn init_event_tokens(&mut self, events: &Events) {
         let mut tokens_clusters_map: HashMap<String, Vec<ClusterId>> = HashMap::new();
         for cd in self.clusters_map.values() {
@@ -225,6 +420,58 @@ impl Clusters {
         self.clusters.len()
     }
 
+    /// Return the tokens of the events in the cluster.
+    #[must_use]
+    pub fn tokens_of(&self, cluster_id: ClusterId) -> HashSet<&str> {
+        self.tokens_clusters_map
+            .iter()
+            .filter_map(|(token, clusters)| {
+                if clusters.binary_search(&cluster_id).is_ok() {
+                    Some(token.as_str())
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    #[must_use]
+    pub fn contains(&self, cluster_id: ClusterId) -> bool {
+        self.clusters_map.contains_key(&cluster_id)
+    }
+
+    /// Return the one-line summary of the cluster. (id, qualifier, size and score)
+    #[must_use]
+    pub fn summary(&self, cluster_id: ClusterId, score_digits: usize) -> Option<String> {
+        self.clusters_map
+            .get(&cluster_id)
+            .map(|c| format!("{:.*}", score_digits, c).trim_start().to_string())
+    }
+
+    /// Return the number of distinct tokens of the events in clusters.
+    #[must_use]
+    pub fn token_count(&self) -> usize {
+        self.tokens_clusters_map.len()
+    }
+
+    #[must_use]
+    pub fn score(&self, cluster_id: ClusterId) -> Option<Score> {
+        self.clusters_map.get(&cluster_id).map(|c| c.score)
+    }
+
+    #[must_use]
+    pub fn qualifier(&self, cluster_id: ClusterId) -> Option<Qualifier> {
+        self.clusters_map.get(&cluster_id).map(|c| c.new_qualifier)
+    }
+
+    /// Return the whole signature of the cluster, not shortened for display.
+    #[must_use]
+    pub fn full_signature(&self, cluster_id: ClusterId) -> Option<&str> {
+        self.clusters_map
+            .get(&cluster_id)
+            .and_then(|c| c.signature.as_deref())
+    }
+
     pub fn size(&self, cluster_id: ClusterId) -> usize {
         self.clusters_map
             .get(&cluster_id)
@@ -237,45 +484,197 @@ impl Clusters {
         self.clusters.is_empty()
     }
 
-    pub fn clear_filter(&mut self, cluster_id: ClusterId) {
-        if let Some(c) = self.clusters_map.get_mut(&cluster_id) {
+    /// Return the reason why `sources` cannot be merged into `target`. The qualifier of a
+    /// source changed from the loaded one would be lost by merging.
+    #[must_use]
+    pub fn merge_conflict(&self, target: ClusterId, sources: &[ClusterId]) -> Option<&'static str> {
+        let qualifier = self.clusters_map.get(&target)?.new_qualifier;
+        let sources = sources.iter().filter_map(|cid| self.clusters_map.get(cid));
+        for c in sources {
+            if c.new_qualifier != c.qualifier || c.new_qualifier != c.saved_qualifier {
+                return Some("changed qualifier");
+            }
+            if c.new_qualifier != qualifier {
+                return Some("different qualifier");
+            }
+        }
+        None
+    }
+
+    /// Return true if the cluster has event filters.
+    #[must_use]
+    pub fn has_filter(&self, cluster_id: ClusterId) -> bool {
+        self.clusters_map
+            .get(&cluster_id)
+            .is_some_and(|c| !c.filter.is_empty())
+    }
+
+    /// Merge the events and tags of `sources` into `target`, and remove `sources`.
+    /// The event filters of `target` are cleared and the highest score is kept.
+    ///
+    /// Return false if `target` is not found or `sources` cannot be merged. (see `merge_conflict`)
+    pub fn merge(&mut self, target: ClusterId, sources: &[ClusterId]) -> bool {
+        if !self.clusters_map.contains_key(&target)
+            || self.merge_conflict(target, sources).is_some()
+        {
+            return false;
+        }
+        let merged: Vec<Members> = sources
+            .iter()
+            .filter(|cid| **cid != target)
+            .filter_map(|cid| self.clusters_map.remove(cid))
+            .collect();
+        if let Some(c) = self.clusters_map.get_mut(&target) {
+            for m in merged {
+                c.size += m.size;
+                c.score = c.score.max(m.score);
+                c.event_ids.extend(m.event_ids);
+                if !m.tags.is_subset(&c.tags) {
+                    c.tags.extend(m.tags);
+                    c.tags_changed = true;
+                }
+            }
             c.filtered_events.clear();
             c.filter.clear();
         }
+        self.clusters
+            .retain(|cid| !sources.contains(cid) || *cid == target);
+        for cs in self.tokens_clusters_map.values_mut() {
+            let len = cs.len();
+            cs.retain(|cid| !sources.contains(cid));
+            if cs.len() < len {
+                cs.push(target);
+                cs.sort_unstable();
+                cs.dedup();
+            }
+        }
+        true
+    }
+
+    /// Clear the event filters of the cluster.
+    ///
+    /// Return false if the cluster has no event filter.
+    pub fn clear_filter(&mut self, cluster_id: ClusterId) -> bool {
+        if let Some(c) = self.clusters_map.get_mut(&cluster_id) {
+            if !c.filter.is_empty() {
+                c.filtered_events.clear();
+                c.filter.clear();
+                return true;
+            }
+        }
+        false
     }
 
-    pub fn print(&self, cid: ClusterId, events: &Events, cfg: &CliConf) {
+    pub fn print_summary(&self, cid: ClusterId) {
         if let Some(c) = self.clusters_map.get(&cid) {
             println!("{}", c);
-            if cfg.is_show_signature_on() {
-                if let Some(sig) = c.signature() {
-                    println!("signature = {}", sig);
+        }
+    }
+
+    /// Write the cluster header, signature, event filters and samples, in colors if `color`
+    /// is set.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if it fails to write to `out`.
+    pub fn write(
+        &self,
+        out: &mut dyn Write,
+        cid: ClusterId,
+        events: &Events,
+        cfg: &CliConf,
+        highlights: &HashSet<String>,
+        color: bool,
+    ) -> io::Result<()> {
+        if let Some(c) = self.clusters_map.get(&cid) {
+            let mut header = String::new();
+            // writing to a string does not fail
+            let _r = c.write_header(&mut header, Some(cfg.score_digits()), color);
+            writeln!(out, "{}", header)?;
+            if !c.tags.is_empty() {
+                writeln!(out, "tags = {}", c.tags().join(", "))?;
+            }
+            let loaded = c.loaded_event_count(events);
+            if loaded < c.event_ids.len() {
+                writeln!(out, "{} of {} events loaded", loaded, c.event_ids.len())?;
+            }
+            let display = cfg.signature_display();
+            if display != SignatureDisplay::Off {
+                let signature = if display == SignatureDisplay::Full {
+                    c.signature.clone()
+                } else {
+                    c.signature()
+                };
+                if let Some(sig) = signature {
+                    writeln!(out, "signature = {}", sig)?;
+                } else if let Some(sig) = c.derived_signature(events) {
+                    writeln!(out, "signature = {} (derived)", sig)?;
                 }
             }
             if !c.filter.is_empty() {
-                println!("Event Filter: {:#?}", c.filter);
+                writeln!(out, "Event Filter: {:#?}", c.filter)?;
             }
             if cfg.is_show_samples_on() {
                 let display_count = cfg.samples_count();
-                let event_ids = if let Some(last) = c.filtered_events.last() {
-                    last
+                let event_ids = c.active_event_ids();
+                // (index in events, message id, the number of identical events)
+                let samples: Vec<(usize, &MessageId, usize)> = if cfg.is_dedup_on() {
+                    dedup_samples(events, event_ids)
                 } else {
-                    &c.event_ids
+                    event_ids
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, message_id)| (idx, message_id, 1))
+                        .collect()
                 };
-                println!();
-                for (idx, message_id) in event_ids.iter().enumerate() {
-                    if idx > display_count {
-                        println!("... {} more events", event_ids.len() - display_count);
+                if display_count == SAMPLES_COUNT_ALL && samples.len() > ALL_SAMPLES_WARNING_COUNT {
+                    let msg = format!(
+                        "showing all {} events. /set samplescount <count> to limit them",
+                        samples.len()
+                    );
+                    warn!("{}", msg);
+                    eprintln!("Warning: {}", msg);
+                }
+                writeln!(out)?;
+                let ordered: Box<dyn Iterator<Item = &(usize, &MessageId, usize)>> =
+                    if cfg.is_samples_tail_on() {
+                        Box::new(samples.iter().rev())
+                    } else {
+                        Box::new(samples.iter())
+                    };
+                for (shown, (idx, message_id, count)) in ordered.enumerate() {
+                    if shown >= display_count {
+                        writeln!(out, "... {} more events", samples.len() - display_count)?;
                         break;
                     }
-                    if let Some(msg) = events.get_message(message_id) {
-                        println!("{}", msg);
+                    if cfg.is_sample_number_on() {
+                        write!(out, "[{}] ", idx)?;
+                    }
+                    let pretty = if cfg.is_pretty_samples_on() {
+                        events.get_pretty_message(message_id).map(Cow::Owned)
+                    } else {
+                        None
+                    };
+                    if let Some(msg) = pretty.or_else(|| events.get_message(message_id)) {
+                        let msg = truncate_line(msg, cfg.line_width());
+                        if cfg.is_highlight_on() {
+                            let token_chars = events.token_chars();
+                            let msg = highlight(&msg, token_chars, highlights, color);
+                            write!(out, "{}", msg)?;
+                        } else {
+                            write!(out, "{}", msg)?;
+                        }
                     } else {
-                        println!("{}", message_id);
+                        write!(out, "{}", message_id)?;
+                    }
+                    if *count > 1 {
+                        write!(out, " (x{})", count)?;
                     }
+                    writeln!(out)?;
                 }
             }
         }
+        Ok(())
     }
 
     #[must_use]
@@ -283,15 +682,33 @@ impl Clusters {
         &self.clusters
     }
 
-    #[must_use]
+    /// Return the clusters in `clusters` matched by the filter. A regex `value` starting
+    /// with `!` matches the clusters having no event matched with the rest of it.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `ft` is `FilterType::Regex` and `value` is an invalid regex.
     pub fn filter_clusters(
         &self,
         clusters: &[ClusterId],
         ft: FilterType,
         op: FilterOp,
         value: &str,
-    ) -> Vec<ClusterId> {
-        clusters
+        cfg: &CliConf,
+        events: &Events,
+    ) -> Result<Vec<ClusterId>> {
+        let regex = if ft == FilterType::Regex {
+            match parse_negatable_pattern(value) {
+                Some((pattern, negate)) => {
+                    Some((build_regex(pattern, cfg.is_regex_case_on())?, negate))
+                }
+                None => return Ok(Vec::new()),
+            }
+        } else {
+            None
+        };
+        let score_epsilon = cfg.score_epsilon();
+        Ok(clusters
             .iter()
             .filter_map(|cid| {
                 if let Some(c) = self.clusters_map.get(cid) {
@@ -314,14 +731,18 @@ impl Clusters {
                                 FilterOp::G => c.score > score,
                                 FilterOp::LE => c.score <= score,
                                 FilterOp::GE => c.score >= score,
-                                FilterOp::EQ => (c.score - score).abs() < f32::EPSILON,
-                                FilterOp::NE => (c.score - score).abs() > f32::EPSILON,
+                                FilterOp::EQ => (c.score - score).abs() <= score_epsilon,
+                                FilterOp::NE => (c.score - score).abs() > score_epsilon,
                             }
                         }
                         FilterType::Qualifier => {
                             let qualifier = Qualifier::from_str(value).unwrap_or_default();
                             c.new_qualifier == qualifier
                         }
+                        FilterType::Tag => c.tags.contains(value),
+                        FilterType::Regex => regex.as_ref().is_some_and(|(re, negate)| {
+                            events.regex_match(re, &c.event_ids).is_empty() == *negate
+                        }),
                         _ => false,
                     };
 
@@ -334,30 +755,6 @@ impl Clusters {
                     None
                 }
             })
-            .collect()
-    }
-
-    pub fn regex_match(
-        &self,
-        clusters: &[ClusterId],
-        pattern: &str,
-        events: &Events,
-    ) -> Result<Vec<ClusterId>> {
-        let re = Regex::new(pattern)?;
-        Ok(clusters
-            .iter()
-            .filter_map(|cid| {
-                if let Some(c) = self.clusters_map.get(cid) {
-                    let matched = events.regex_match(&re, &c.event_ids);
-                    if matched.is_empty() {
-                        None
-                    } else {
-                        Some(*cid)
-                    }
-                } else {
-                    None
-                }
-            })
             .collect())
     }
 
@@ -365,26 +762,16 @@ impl Clusters {
         &self,
         cluster_id: ClusterId,
         pattern: &str,
+        case_sensitive: bool,
         events: &Events,
     ) -> Result<Option<Vec<MessageId>>> {
-        let mut negate: bool = false;
-        let pattern = if pattern.starts_with('!') {
-            if pattern.len() == 1 {
-                return Ok(None);
-            }
-            negate = true;
-            pattern.get(1..).unwrap_or(pattern)
-        } else {
-            pattern
+        let Some((pattern, negate)) = parse_negatable_pattern(pattern) else {
+            return Ok(None);
         };
 
-        let re = Regex::new(pattern)?;
+        let re = build_regex(pattern, case_sensitive)?;
         Ok(self.clusters_map.get(&cluster_id).map(|c| {
-            let cluster_event_ids = if let Some(last) = c.filtered_events.last() {
-                last
-            } else {
-                &c.event_ids
-            };
+            let cluster_event_ids = c.active_event_ids();
             let matched = events.regex_match(&re, cluster_event_ids);
             if negate {
                 let set_matched: HashSet<_> = matched.into_iter().collect();
@@ -396,6 +783,13 @@ impl Clusters {
         }))
     }
 
+    #[must_use]
+    pub fn active_event_ids(&self, cluster_id: ClusterId) -> &[MessageId] {
+        self.clusters_map
+            .get(&cluster_id)
+            .map_or(&[], Members::active_event_ids)
+    }
+
     pub fn set_filtered(&mut self, cluster_id: ClusterId, matched: Vec<MessageId>, pattern: &str) {
         if let Some(c) = self.clusters_map.get_mut(&cluster_id) {
             c.filter.push(pattern.to_string());
@@ -403,6 +797,207 @@ impl Clusters {
         }
     }
 
+    /// Return the sorted message ids of outliers which are also in other clusters.
+    #[must_use]
+    pub fn outlier_overlap(&self) -> Vec<MessageId> {
+        let outliers = match self.clusters_map.get(&CLUSTER_ID_FOR_OUTLIERS) {
+            Some(c) => c.event_ids.iter().collect::<HashSet<_>>(),
+            None => return Vec::new(),
+        };
+        let mut overlap: Vec<MessageId> = self
+            .clusters_map
+            .values()
+            .filter(|c| c.id != CLUSTER_ID_FOR_OUTLIERS)
+            .flat_map(|c| c.event_ids.iter())
+            .filter(|message_id| outliers.contains(message_id))
+            .cloned()
+            .collect();
+        overlap.sort_unstable();
+        overlap.dedup();
+        overlap
+    }
+
+    /// Return (min, max) of the scores of clusters, except outliers. `None` if no clusters.
+    #[must_use]
+    pub fn score_range(&self) -> Option<(Score, Score)> {
+        self.clusters_map
+            .values()
+            .filter(|c| c.id != CLUSTER_ID_FOR_OUTLIERS)
+            .map(|c| c.score)
+            .fold(None, |range, score| match range {
+                Some((min, max)) => Some((Score::min(min, score), Score::max(max, score))),
+                None => Some((score, score)),
+            })
+    }
+
+    /// Return the number of clusters whose score is `threshold` or higher.
+    #[must_use]
+    pub fn count_score_above(&self, threshold: Score) -> usize {
+        self.clusters_map
+            .values()
+            .filter(|c| c.id != CLUSTER_ID_FOR_OUTLIERS && c.score >= threshold)
+            .count()
+    }
+
+    /// Count clusters by their size bucketed by powers of ten. (1, 2-9, 10-99, 100-999, ...)
+    ///
+    /// Return (bucket, the number of clusters) in ascending order of bucket up to the largest one.
+    #[must_use]
+    pub fn size_histogram(&self, clusters: &[ClusterId]) -> Vec<(String, usize)> {
+        let mut counts: Vec<usize> = Vec::new();
+        for cid in clusters {
+            if let Some(c) = self.clusters_map.get(cid) {
+                let bucket = if c.size <= 1 {
+                    0
+                } else {
+                    c.size.to_string().len()
+                };
+                if counts.len() <= bucket {
+                    counts.resize(bucket + 1, 0);
+                }
+                counts[bucket] += 1;
+            }
+        }
+        counts
+            .into_iter()
+            .enumerate()
+            .map(|(bucket, count)| {
+                let range = match bucket {
+                    0 => "1".to_string(),
+                    1 => "2-9".to_string(),
+                    _ => {
+                        let low = (1..bucket).fold(1_usize, |n, _| n * 10);
+                        format!("{}-{}", low, low * 10 - 1)
+                    }
+                };
+                (range, count)
+            })
+            .collect()
+    }
+
+    /// Group clusters having the same non-empty signature.
+    ///
+    /// Return the groups of more than one cluster. Clusters are sorted in each group.
+    #[must_use]
+    pub fn same_signature_groups(&self, clusters: &[ClusterId]) -> Vec<Vec<ClusterId>> {
+        let mut groups: HashMap<&str, Vec<ClusterId>> = HashMap::new();
+        for cid in clusters {
+            if let Some(sig) = self.full_signature(*cid) {
+                if !sig.trim().is_empty() {
+                    groups.entry(sig).or_default().push(*cid);
+                }
+            }
+        }
+        let mut groups: Vec<_> = groups
+            .into_values()
+            .filter(|group| group.len() > 1)
+            .map(|mut group| {
+                group.sort_unstable();
+                group
+            })
+            .collect();
+        groups.sort_unstable();
+        groups
+    }
+
+    /// Group clusters by the normalized prefix of their signatures.
+    /// Whitespaces are collapsed and the signature is truncated to `SIGNATURE_GROUP_PREFIX_LENGTH` chars.
+    ///
+    /// Return (signature prefix, the number of clusters) sorted by count in descending order.
+    #[must_use]
+    pub fn signature_groups(&self, clusters: &[ClusterId]) -> Vec<(String, usize)> {
+        let mut groups: HashMap<String, usize> = HashMap::new();
+        for cid in clusters {
+            if let Some(c) = self.clusters_map.get(cid) {
+                let key = c.signature.as_deref().map_or_else(
+                    || SIGNATURE_GROUP_NONE.to_string(),
+                    |sig| {
+                        sig.split_whitespace()
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                            .chars()
+                            .take(SIGNATURE_GROUP_PREFIX_LENGTH)
+                            .collect()
+                    },
+                );
+                *groups.entry(key).or_insert(0) += 1;
+            }
+        }
+        let mut groups: Vec<_> = groups.into_iter().collect();
+        groups.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        groups
+    }
+
+    /// Return the clusters whose qualifier is changed by user or which have tags.
+    /// (cluster id, qualifier, tags)
+    #[must_use]
+    pub fn changed_qualifiers(&self) -> Vec<(ClusterId, Qualifier, Vec<String>)> {
+        let mut changed: Vec<_> = self
+            .clusters_map
+            .values()
+            .filter(|c| c.new_qualifier != c.qualifier || !c.tags.is_empty())
+            .map(|c| (c.id, c.new_qualifier, c.tags()))
+            .collect();
+        changed.sort_unstable();
+        changed
+    }
+
+    /// Return the number of clusters whose qualifier is changed from the loaded one by the new
+    /// qualifier, the number of tags and the number of tagged clusters.
+    #[must_use]
+    pub fn triage_summary(&self) -> (BTreeMap<Qualifier, usize>, usize, usize) {
+        let mut triaged: BTreeMap<Qualifier, usize> = BTreeMap::new();
+        let (mut tags, mut tagged) = (0, 0);
+        for c in self.clusters_map.values() {
+            if c.new_qualifier != c.qualifier {
+                *triaged.entry(c.new_qualifier).or_default() += 1;
+            }
+            if !c.tags.is_empty() {
+                tags += c.tags.len();
+                tagged += 1;
+            }
+        }
+        (triaged, tags, tagged)
+    }
+
+    /// Return the number of clusters whose qualifier or tags are changed after the last save.
+    #[must_use]
+    pub fn unsaved_count(&self) -> usize {
+        self.clusters_map
+            .values()
+            .filter(|c| c.new_qualifier != c.saved_qualifier || c.tags_changed)
+            .count()
+    }
+
+    pub fn mark_saved(&mut self) {
+        for c in self.clusters_map.values_mut() {
+            c.saved_qualifier = c.new_qualifier;
+            c.tags_changed = false;
+        }
+    }
+
+    /// Add or remove the tag of the cluster. Return false if nothing is changed.
+    pub fn set_tag(&mut self, cid: ClusterId, tag: &str, add: bool) -> bool {
+        if let Some(c) = self.clusters_map.get_mut(&cid) {
+            let changed = if add {
+                c.tags.insert(tag.to_string())
+            } else {
+                c.tags.remove(tag)
+            };
+            c.tags_changed |= changed;
+            return changed;
+        }
+        false
+    }
+
+    #[must_use]
+    pub fn tags(&self, cid: ClusterId) -> Vec<String> {
+        self.clusters_map
+            .get(&cid)
+            .map(Members::tags)
+            .unwrap_or_default()
+    }
+
     pub fn set_qualifier(&mut self, cid: ClusterId, qualifier: Qualifier) -> bool {
         if let Some(c) = self.clusters_map.get_mut(&cid) {
             return c.set_qualifier(qualifier);
@@ -410,3 +1005,214 @@ impl Clusters {
         false
     }
 }
+
+/// Paint the words of `msg` in `tokens` bold red if `color` is set. Words are compared in
+/// lowercase.
+fn highlight(msg: &str, token_chars: &[char], tokens: &HashSet<String>, color: bool) -> String {
+    let bold_red = crate::style_if(color, crate::themed(Colour::Red).bold());
+    parser::split_words(msg, token_chars)
+        .into_iter()
+        .map(|(is_word, s)| {
+            if is_word && tokens.contains(&s.to_lowercase()) {
+                bold_red.paint(s).to_string()
+            } else {
+                s.to_string()
+            }
+        })
+        .collect()
+}
+
+/// Split the leading `!` of `pattern`, which negates the match.
+///
+/// Return `(pattern without '!', negate)`, or `None` if nothing is left to match:
+/// an empty pattern or a lone `!`.
+#[must_use]
+pub fn parse_negatable_pattern(pattern: &str) -> Option<(&str, bool)> {
+    let (pattern, negate) = match pattern.strip_prefix('!') {
+        Some(p) => (p, true),
+        None => (pattern, false),
+    };
+    if pattern.is_empty() {
+        None
+    } else {
+        Some((pattern, negate))
+    }
+}
+
+/// The score of the cluster. A missing or NaN score is 0.
+fn valid_score(cluster_id: ClusterId, score: Option<Score>) -> Score {
+    match score {
+        Some(score) if score.is_nan() => {
+            warn!("NaN score of cluster {} is replaced with 0", cluster_id);
+            0.0
+        }
+        score => score.unwrap_or_default(),
+    }
+}
+
+/// The message ids of the raw event lines of outliers. They are the fields at `key_index`.
+fn outlier_event_ids(outliers: &[String], delimiter: char, key_index: usize) -> Vec<MessageId> {
+    outliers
+        .iter()
+        .filter_map(|raw| {
+            let s: Vec<_> = raw.split(delimiter).collect();
+            s.get(key_index).map(|msg_id| (*msg_id).to_string())
+        })
+        .collect()
+}
+
+/// Truncate `line` to `width` bytes on a UTF-8 boundary, with its length appended.
+fn truncate_line(line: Cow<str>, width: usize) -> Cow<str> {
+    if line.len() <= width {
+        return line;
+    }
+    let mut end = width;
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    Cow::Owned(format!("{}… ({})", &line[..end], line.len()))
+}
+
+/// Collapse the events of the same content except their keys into the first of them.
+///
+/// Return (index in `event_ids`, message id, the number of identical events).
+fn dedup_samples<'a>(
+    events: &Events,
+    event_ids: &'a [MessageId],
+) -> Vec<(usize, &'a MessageId, usize)> {
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    let mut samples: Vec<(usize, &MessageId, usize)> = Vec::new();
+    for (idx, message_id) in event_ids.iter().enumerate() {
+        // events not loaded are not collapsed
+        if let Some(content) = events.content_without_key(message_id) {
+            if let Some(pos) = positions.get(&content) {
+                samples[*pos].2 += 1;
+                continue;
+            }
+            positions.insert(content, samples.len());
+        }
+        samples.push((idx, message_id, 1));
+    }
+    samples
+}
+
+/// Compile `pattern`, ignoring case if `case_sensitive` is off.
+fn build_regex(pattern: &str, case_sensitive: bool) -> Result<Regex> {
+    if case_sensitive {
+        Ok(Regex::new(pattern)?)
+    } else {
+        Ok(Regex::new(&format!("(?i){}", pattern))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Load the clusters file of `content` with empty labels. The files are written in the
+    /// temporary directory and removed after loading.
+    fn clusters(name: &str, content: &str) -> Clusters {
+        let dir = std::env::temp_dir().join(format!("labeler-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).expect("writable temp dir");
+        let (labels_path, clusters_path) = (dir.join("labels.json"), dir.join("clusters.json"));
+        std::fs::write(
+            &labels_path,
+            r#"{"representative_labels":0,"event_labels":0,"representative":[],"events":[]}"#,
+        )
+        .expect("writable temp dir");
+        std::fs::write(&clusters_path, content).expect("writable temp dir");
+        let labels = Labels::new(&labels_path.display().to_string());
+        let clusters = labels.and_then(|labels| {
+            Clusters::new(&clusters_path.display().to_string(), &labels, ',', 0)
+        });
+        std::fs::remove_dir_all(&dir).expect("removable temp dir");
+        clusters.expect("valid clusters")
+    }
+
+    #[test]
+    fn nan_score_is_zero() {
+        assert!(valid_score(1, Some(Score::NAN)).abs() < Score::EPSILON);
+        assert!(valid_score(1, None).abs() < Score::EPSILON);
+        assert!((valid_score(1, Some(0.5)) - 0.5).abs() < Score::EPSILON);
+        assert!(valid_score(1, Some(Score::INFINITY)).is_infinite());
+    }
+
+    #[test]
+    fn first_of_duplicate_clusters() {
+        let clusters = clusters(
+            "clusters_dup",
+            r#"{"detector_id":1,"events_count":3,"clusters_count":2,"outlier_count":0,
+                "clusters":[
+                    {"cluster_id":1,"cluster_size":2,"signature":null,"score":0.9,"events":["e1","e2"]},
+                    {"cluster_id":1,"cluster_size":1,"signature":null,"score":0.1,"events":["e3"]}],
+                "outliers":[]}"#,
+        );
+        assert_eq!(clusters.cluster_list(), &vec![1]);
+        assert_eq!(clusters.size(1), 2);
+        assert_eq!(clusters.score(1), Some(0.9));
+        assert_eq!(clusters.event_ids(), vec!["e1", "e2"]);
+    }
+
+    #[test]
+    fn outliers_split_by_delimiter() {
+        let mut clusters = clusters(
+            "clusters_outliers",
+            r#"{"detector_id":1,"events_count":3,"clusters_count":1,"outlier_count":1,
+                "clusters":[
+                    {"cluster_id":1,"cluster_size":2,"signature":null,"score":0.9,"events":["e1","e2"]}],
+                "outliers":["e3;HEAD /robots.txt"]}"#,
+        );
+        let mut event_ids = clusters.event_ids_by_delimiter(';', 0);
+        event_ids.sort();
+        assert_eq!(event_ids, vec!["e1", "e2", "e3"]);
+        assert!(clusters
+            .event_ids()
+            .contains(&String::from("e3;HEAD /robots.txt")));
+
+        clusters.set_delimiter(';', 0);
+        let mut event_ids = clusters.event_ids();
+        event_ids.sort();
+        assert_eq!(event_ids, vec!["e1", "e2", "e3"]);
+    }
+
+    #[test]
+    fn negatable_pattern() {
+        assert_eq!(parse_negatable_pattern("foo"), Some(("foo", false)));
+        assert_eq!(parse_negatable_pattern("!foo"), Some(("foo", true)));
+        assert_eq!(parse_negatable_pattern("!"), None);
+        assert_eq!(parse_negatable_pattern(""), None);
+    }
+
+    #[test]
+    fn merge_keeps_changed_qualifiers() {
+        let mut clusters = clusters(
+            "clusters_merge",
+            r#"{"detector_id":1,"events_count":4,"clusters_count":4,"outlier_count":0,
+                "clusters":[
+                    {"cluster_id":1,"cluster_size":1,"signature":"GET /","score":0.9,"events":["e1"]},
+                    {"cluster_id":2,"cluster_size":1,"signature":"GET /","score":0.5,"events":["e2"]},
+                    {"cluster_id":3,"cluster_size":1,"signature":"GET /","score":0.1,"events":["e3"]},
+                    {"cluster_id":4,"cluster_size":1,"signature":"GET /","score":0.1,"events":["e4"]}],
+                "outliers":[]}"#,
+        );
+        clusters.set_qualifier(2, Qualifier::Benign);
+        assert_eq!(
+            clusters.merge_conflict(1, &[2, 3]),
+            Some("changed qualifier")
+        );
+        assert!(!clusters.merge(1, &[2, 3]));
+        assert_eq!(clusters.unsaved_count(), 1);
+
+        clusters.set_qualifier(1, Qualifier::Benign);
+        clusters.set_qualifier(2, Qualifier::Unknown);
+        assert_eq!(
+            clusters.merge_conflict(1, &[2, 3]),
+            Some("different qualifier")
+        );
+        clusters.set_qualifier(1, Qualifier::Unknown);
+        assert_eq!(clusters.merge_conflict(1, &[2, 3]), None);
+        assert!(clusters.merge(1, &[2, 3]));
+        assert_eq!(clusters.cluster_list(), &vec![1, 4]);
+        assert_eq!(clusters.size(1), 3);
+    }
+}
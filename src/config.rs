@@ -1,9 +1,15 @@
+use crate::parser::default_token_chars;
 use crate::EventType;
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
-use std::{fs::File, io::BufReader, path::Path};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum ColumnType {
     Datetime,
@@ -15,6 +21,36 @@ pub enum ColumnType {
     Binary,
 }
 
+/// Format of the events file
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventsFormat {
+    /// fields separated by `delimiter`
+    #[default]
+    Csv,
+    /// a json object per line. fields are looked up by alias.
+    Jsonl,
+}
+
+/// Token normalizer applied after tokenizing. (see `parser::TokenNormalizer`)
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NormalizerKind {
+    #[default]
+    Default,
+    TrimDots,
+}
+
+/// Email addresses extracted as whole tokens in addition to the ordinary tokens.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailTokens {
+    #[default]
+    Off,
+    Address,
+    WithDomain, // the address and its domain
+}
+
 pub trait Load
 where
     for<'de> Self: Deserialize<'de> + Sized,
@@ -42,8 +78,46 @@ pub struct Config {
     key_column: String, // must match alias field name
     #[serde(default = "default_delimiter")]
     delimiter: char,
+    #[serde(default)]
+    profiles: HashMap<String, HashMap<String, f64>>, // profile name -> (alias -> weight)
+    #[serde(default)]
+    comment_char: Option<char>, // lines of events starting with this are ignored
+    #[serde(default)]
+    header_rows: usize, // the number of leading lines of events to ignore
+    #[serde(default)]
+    record_start: Option<String>, // regex of the first line of a multi-line event
+    #[serde(default)]
+    key_regex: Option<String>, // regex capturing the key of an event instead of key_column
+    #[serde(default)]
+    signature_column: Option<usize>, // column of events used as the signature if a cluster has none
+    #[serde(default)]
+    intern_fields: bool, // share the same field values between events to save memory
+    #[serde(default)]
+    events_format: EventsFormat,
+    #[serde(default)]
+    token_normalizer: NormalizerKind,
+    #[serde(default)]
+    token_chars: Option<String>, // characters of tokens besides alphanumerics
+    #[serde(default)]
+    email_tokens: EmailTokens,
+    #[serde(default)]
+    tidb_versions: Vec<String>, // expected versions of tidb. empty if any version is allowed
+    #[serde(default = "default_output_qualifiers")]
+    output_qualifiers: String,
+    #[serde(default)]
+    autosave_on_exit: bool,
+    #[serde(default = "default_max_filter_layers")]
+    max_filter_layers: usize, // the maximum depth of filter layers including the base layer
 }
 
+pub const DEFAULT_PROFILE: &str = "default";
+
+// file names looked up by `--config-dir`
+const CONVENTION_CLUSTERS: &str = "clusters.json";
+const CONVENTION_LABELS: &str = "labels.json";
+const CONVENTION_EVENTS: &str = "events.csv";
+const CONVENTION_TIDB: &str = "tidb";
+
 fn default_delimiter() -> char {
     ','
 }
@@ -52,6 +126,14 @@ fn default_keycolumn() -> String {
     "uid".to_string()
 }
 
+fn default_output_qualifiers() -> String {
+    "qualifiers.json".to_string()
+}
+
+fn default_max_filter_layers() -> usize {
+    32
+}
+
 #[derive(Debug, Deserialize)]
 struct ColumnFormat {
     data_type: ColumnType,
@@ -102,6 +184,145 @@ impl Config {
             .collect()
     }
 
+    /// Pair the feature columns with their data types.
+    #[must_use]
+    pub fn feature_types(&self, features: &[usize]) -> Vec<(usize, ColumnType)> {
+        features
+            .iter()
+            .filter_map(|idx| self.format.get(*idx).map(|col| (*idx, col.data_type)))
+            .collect()
+    }
+
+    /// Returns the feature columns of the named weight profile.
+    /// Columns not listed in the profile are treated as weight 0.
+    /// The `default` profile uses the weights in `format`.
+    #[must_use]
+    pub fn profile_features(&self, name: &str) -> Option<Vec<usize>> {
+        if name == DEFAULT_PROFILE && !self.profiles.contains_key(name) {
+            return Some(self.features());
+        }
+        let weights = self.profiles.get(name)?;
+        Some(
+            self.format
+                .iter()
+                .enumerate()
+                .filter_map(|(idx, col)| {
+                    if weights.get(&col.alias).copied().unwrap_or_default() > 0.0 {
+                        return Some(idx);
+                    }
+
+                    None
+                })
+                .collect(),
+        )
+    }
+
+    #[must_use]
+    pub fn profile_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+        if !self.profiles.contains_key(DEFAULT_PROFILE) {
+            names.push(DEFAULT_PROFILE);
+        }
+        names.sort_unstable();
+        names
+    }
+
+    #[must_use]
+    pub fn output_qualifiers(&self) -> &str {
+        &self.output_qualifiers
+    }
+
+    #[must_use]
+    pub fn autosave_on_exit(&self) -> bool {
+        self.autosave_on_exit
+    }
+
+    #[must_use]
+    pub fn max_filter_layers(&self) -> usize {
+        self.max_filter_layers
+    }
+
+    /// Return the aliases of columns in order.
+    #[must_use]
+    pub fn aliases(&self) -> Vec<String> {
+        self.format.iter().map(|col| col.alias.clone()).collect()
+    }
+
+    /// Return the aliases of columns in order if the events file is jsonl format.
+    #[must_use]
+    pub fn json_columns(&self) -> Option<Vec<String>> {
+        if self.events_format == EventsFormat::Jsonl {
+            Some(self.aliases())
+        } else {
+            None
+        }
+    }
+
+    #[must_use]
+    pub fn tidb_versions(&self) -> &[String] {
+        &self.tidb_versions
+    }
+
+    #[must_use]
+    pub fn email_tokens(&self) -> EmailTokens {
+        self.email_tokens
+    }
+
+    #[must_use]
+    pub fn token_normalizer(&self) -> NormalizerKind {
+        self.token_normalizer
+    }
+
+    /// Return the characters treated as a part of tokens besides alphanumerics. The default
+    /// depends on the event type. (see `parser::default_token_chars`)
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `token_chars` has whitespaces.
+    pub fn token_chars(&self) -> Result<Vec<char>> {
+        match &self.token_chars {
+            Some(chars) if chars.chars().any(char::is_whitespace) => {
+                Err(anyhow!("token_chars must not have whitespaces"))
+            }
+            Some(chars) => Ok(chars.chars().collect()),
+            None => Ok(default_token_chars(self.event_type).to_vec()),
+        }
+    }
+
+    #[must_use]
+    pub fn comment_char(&self) -> Option<char> {
+        self.comment_char
+    }
+
+    #[must_use]
+    pub fn header_rows(&self) -> usize {
+        self.header_rows
+    }
+
+    /// The pattern of the first line of an event. Lines not matching it are continuation
+    /// lines of the previous event.
+    #[must_use]
+    pub fn record_start(&self) -> Option<&str> {
+        self.record_start.as_deref()
+    }
+
+    /// The pattern whose first capture group is the key of an event, for logs without
+    /// a key column.
+    #[must_use]
+    pub fn key_regex(&self) -> Option<&str> {
+        self.key_regex.as_deref()
+    }
+
+    #[must_use]
+    pub fn signature_column(&self) -> Option<usize> {
+        self.signature_column
+    }
+
+    #[must_use]
+    pub fn intern_fields(&self) -> bool {
+        self.intern_fields
+    }
+
     #[must_use]
     pub fn key_field(&self) -> Option<usize> {
         self.format
@@ -146,4 +367,94 @@ impl Config {
             }
         }
     }
+
+    /// Synthesize config from the files in `dir` which follow the naming convention.
+    /// * `clusters.json`, `labels.json`, `events.csv` and `tidb/` directory
+    ///
+    /// All columns of `events.csv` are `utf8` type. The first column is the key column(`uid`)
+    /// and the others are feature columns of weight 1.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if any of the files is missing or `events.csv` is empty.
+    pub fn from_dir(dir: &str) -> Result<Self> {
+        let dir = Path::new(dir);
+        let path_of = |name: &str| -> Result<String> {
+            let path = dir.join(name);
+            if path.exists() {
+                path.to_str()
+                    .map(ToString::to_string)
+                    .ok_or_else(|| anyhow!("invalid path {}", path.display()))
+            } else {
+                Err(anyhow!("{} not found", path.display()))
+            }
+        };
+        let input_clusters = path_of(CONVENTION_CLUSTERS)?;
+        let input_labels = path_of(CONVENTION_LABELS)?;
+        let input_log = path_of(CONVENTION_EVENTS)?;
+        // the tidb files are found by walking the directory. (see `tidb::files_from`)
+        let tidb = path_of(CONVENTION_TIDB)?;
+
+        let delimiter = default_delimiter();
+        let mut first_line = String::new();
+        BufReader::new(File::open(&input_log)?).read_line(&mut first_line)?;
+        let first_line = first_line.trim_end();
+        if first_line.is_empty() {
+            return Err(anyhow!("{} is empty", input_log));
+        }
+        let column_len = first_line.split(delimiter).count();
+        let key_column = default_keycolumn();
+        let format = (0..column_len)
+            .map(|idx| ColumnFormat {
+                data_type: ColumnType::Utf8,
+                weight: if idx == 0 { 0.0 } else { 1.0 },
+                format: None,
+                alias: if idx == 0 {
+                    key_column.clone()
+                } else {
+                    format!("column{}", idx)
+                },
+            })
+            .collect();
+
+        Ok(Config {
+            event_type: EventType::default(),
+            time_column: 0,
+            format,
+            input_log,
+            input_clusters,
+            input_labels,
+            tidb,
+            key_column,
+            delimiter,
+            profiles: HashMap::new(),
+            comment_char: None,
+            header_rows: 0,
+            record_start: None,
+            key_regex: None,
+            signature_column: None,
+            intern_fields: false,
+            events_format: EventsFormat::default(),
+            token_normalizer: NormalizerKind::default(),
+            token_chars: None,
+            email_tokens: EmailTokens::default(),
+            tidb_versions: Vec::new(),
+            output_qualifiers: default_output_qualifiers(),
+            autosave_on_exit: false,
+            max_filter_layers: default_max_filter_layers(),
+        })
+    }
+
+    /// # Panics
+    /// * if the files in `dir` do not follow the naming convention
+    #[must_use]
+    pub fn init_from_dir(dir: &str) -> Self {
+        match Config::from_dir(dir) {
+            Ok(c) => c,
+            Err(e) => {
+                log::error!("{:?}. use --config-path to specify config file.", e);
+                std::process::exit(-1);
+            }
+        }
+    }
 }
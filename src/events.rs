@@ -1,24 +1,68 @@
-use crate::config::Config;
-use crate::{parser, MessageId};
-use anyhow::{anyhow, Result};
-use log::info;
+use crate::config::{ColumnType, Config, EmailTokens};
+use crate::parser::{self, TokenNormalizer};
+use crate::MessageId;
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
 use regex::Regex;
-use std::collections::{HashMap, HashSet};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::iter::Peekable;
+use std::sync::Arc;
+
+/// Raw event line, or its fields shared with other events if `intern_fields` is set in config.
+#[derive(Clone)]
+enum Content {
+    Line(String),
+    Fields(Vec<Arc<str>>),
+}
+
+impl Default for Content {
+    fn default() -> Self {
+        Content::Line(String::new())
+    }
+}
+
+impl Content {
+    fn fields(&self, delimiter: char, json_columns: Option<&[String]>) -> Vec<String> {
+        match (self, json_columns) {
+            (Content::Line(line), Some(columns)) => json_fields(line, columns).unwrap_or_default(),
+            (Content::Line(line), None) => line.split(delimiter).map(ToString::to_string).collect(),
+            (Content::Fields(fields), _) => fields.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    fn as_str(&self, delimiter: char) -> Cow<'_, str> {
+        match self {
+            Content::Line(line) => Cow::Borrowed(line),
+            Content::Fields(fields) => Cow::Owned(fields.join(&delimiter.to_string())),
+        }
+    }
+}
 
 #[derive(Default, Clone)]
 pub struct Message {
     _id: MessageId,
-    content: String,
+    content: Content,
     tokens: Vec<String>,
 }
 
 #[derive(Default, Clone)]
 pub struct Events {
     events: HashMap<MessageId, Message>,
-    // tokens_events_map: HashMap<Vec<String>, Vec<MessageId>>,
-    // outliers: Vec<MessageId>,
+    delimiter: char,
+    json_columns: Option<Vec<String>>,
+    aliases: Vec<String>,
+    key_column: Option<usize>, // None if keys are extracted by `key_regex`
+    token_chars: Vec<char>,
+    email_tokens: EmailTokens,
+    token_sources: HashMap<String, BTreeSet<usize>>, // token -> columns where it is extracted
+    skipped: usize, // the number of lines whose column count does not match or which is not UTF-8
+    notfound: usize, // the number of lines not referenced by clusters
+                    // tokens_events_map: HashMap<Vec<String>, Vec<MessageId>>,
+                    // outliers: Vec<MessageId>,
 }
 
 impl Events {
@@ -29,52 +73,175 @@ impl Events {
     ///
     /// Will return Err if it fails to open events file.
     pub fn new(cfg: &Config, event_ids: Vec<MessageId>) -> Result<Self> {
+        Self::load(
+            cfg,
+            event_ids,
+            &cfg.feature_types(&cfg.features()),
+            cfg.delimiter(),
+        )
+    }
+
+    /// Load events with the specified feature columns and delimiter instead of those in `cfg`.
+    ///
+    /// # Panics
+    /// * if `key_column` field does not find in column format aliases
+    ///
+    /// # Errors
+    ///
+    /// Will return Err if it fails to open events file, or `record_start` or `key_regex` is
+    /// an invalid regex.
+    pub fn load(
+        cfg: &Config,
+        event_ids: Vec<MessageId>,
+        features: &[(usize, ColumnType)],
+        delimiter: char,
+    ) -> Result<Self> {
         let key_idx = cfg
             .key_field()
             .ok_or_else(|| anyhow!("key_field does not set"))?;
-        let features = cfg.features();
+        let normalizer = parser::normalizer(cfg.token_normalizer());
+        let token_chars = cfg.token_chars()?;
+        let email_tokens = cfg.email_tokens();
         let column_len = cfg.column_len();
-        let delimiter = cfg.delimiter();
+        let json_columns = cfg.json_columns();
+        let intern_fields = cfg.intern_fields() && json_columns.is_none();
         let event_ids: HashSet<MessageId> = event_ids.into_iter().collect();
+        let mut pool: HashSet<Arc<str>> = HashSet::new();
+        let (mut content_bytes, mut pool_bytes) = (0, 0);
 
+        let record_start = cfg
+            .record_start()
+            .map(Regex::new)
+            .transpose()
+            .context("invalid record_start")?;
+        let key_regex = cfg
+            .key_regex()
+            .map(Regex::new)
+            .transpose()
+            .context("invalid key_regex")?;
+        if key_regex.as_ref().is_some_and(|re| re.captures_len() < 2) {
+            return Err(anyhow!("key_regex has no capture group"));
+        }
+        let key_regex_set = key_regex.is_some();
         let file = File::open(cfg.events())?;
-        let lines = BufReader::new(file).lines();
+        let comment_char = cfg.comment_char();
+        let mut invalid = 0;
+        let lines = BufReader::new(file)
+            .lines()
+            .map_while(|line| match line {
+                Ok(line) => Some(Some(line)),
+                // the line is consumed, so reading goes on to the next one
+                Err(e) if e.kind() == ErrorKind::InvalidData => {
+                    invalid += 1;
+                    Some(None)
+                }
+                Err(e) => {
+                    let msg = format!("stop reading events: {}", e);
+                    warn!("{}", msg);
+                    eprintln!("Warning: {}", msg);
+                    None
+                }
+            })
+            .flatten()
+            .skip(cfg.header_rows())
+            .filter(|line| !comment_char.is_some_and(|c| line.starts_with(c)));
         let mut events = HashMap::new();
+        let mut token_sources = HashMap::new();
         let mut skipped = 0;
         let mut notfound = 0;
-        for line in lines.flatten() {
-            let log: Vec<_> = line.split(delimiter).collect();
-            if log.len() != column_len {
+        for (idx, line) in (Records {
+            lines: lines.peekable(),
+            record_start,
+        })
+        .enumerate()
+        {
+            let json_log: Vec<String>;
+            let log: Vec<&str> = if let Some(columns) = &json_columns {
+                if let Some(v) = json_fields(&line, columns) {
+                    json_log = v;
+                    json_log.iter().map(String::as_str).collect()
+                } else {
+                    skipped += 1;
+                    continue;
+                }
+            } else {
+                line.split(delimiter).collect()
+            };
+            // free-form logs do not have the same number of fields in every line
+            if key_regex.is_none() && log.len() != column_len {
                 skipped += 1;
                 continue;
             }
-            let key = if let Some(key) = log.get(key_idx) {
-                if event_ids.contains(*key) {
-                    key
-                } else {
+            let key = if let Some(re) = &key_regex {
+                Some(
+                    re.captures(&line)
+                        .and_then(|c| c.get(1))
+                        .map_or_else(|| synthetic_key(idx), |m| m.as_str().to_string()),
+                )
+            } else {
+                log.get(key_idx).map(|key| (*key).to_string())
+            };
+            let key = match key {
+                Some(key) if event_ids.contains(&key) => key,
+                _ => {
                     notfound += 1;
                     continue;
                 }
+            };
+            let tokens = extract_features(
+                &log,
+                features,
+                &token_chars,
+                normalizer.as_ref(),
+                email_tokens,
+                &mut token_sources,
+            );
+            let content = if intern_fields {
+                content_bytes += line.len();
+                Content::Fields(
+                    log.iter()
+                        .map(|field| {
+                            if let Some(v) = pool.get(*field) {
+                                v.clone()
+                            } else {
+                                let v: Arc<str> = Arc::from(*field);
+                                pool_bytes += v.len();
+                                pool.insert(v.clone());
+                                v
+                            }
+                        })
+                        .collect(),
+                )
             } else {
-                notfound += 1;
-                continue;
+                Content::Line(line)
             };
-            let mut tokens = Vec::new();
-            for feature_idx in &features {
-                if let Some(value) = log.get(*feature_idx) {
-                    tokens.extend(parser::extract_tokens(value));
-                }
-            }
             events.insert(
-                (*key).to_string(),
+                key.clone(),
                 Message {
-                    _id: (*key).to_string(),
-                    content: line,
+                    _id: key,
+                    content,
                     tokens,
                 },
             );
         }
+        if invalid > 0 {
+            let msg = format!(
+                "{} lines of events are not valid UTF-8 and skipped",
+                invalid
+            );
+            warn!("{}", msg);
+            eprintln!("Warning: {}", msg);
+            skipped += invalid;
+        }
         info!("{} skipped events, {} not found", skipped, notfound);
+        if intern_fields {
+            info!(
+                "{} distinct field values interned. {} bytes of events are stored in {} bytes",
+                pool.len(),
+                content_bytes,
+                pool_bytes
+            );
+        }
 
         // let mut tokens_events_map: HashMap<Vec<String>, Vec<MessageId>> = HashMap::new();
         // for (id, msg) in &events {
@@ -86,6 +253,15 @@ impl Events {
 
         Ok(Self {
             events,
+            delimiter,
+            json_columns,
+            aliases: cfg.aliases(),
+            key_column: if key_regex_set { None } else { Some(key_idx) },
+            token_chars,
+            email_tokens,
+            token_sources,
+            skipped,
+            notfound,
             // tokens_events_map,
             // outliers: Vec::new(),
         })
@@ -101,18 +277,109 @@ impl Events {
         self.events.len()
     }
 
+    #[must_use]
+    pub fn contains(&self, message_id: &MessageId) -> bool {
+        self.events.contains_key(message_id)
+    }
+
+    #[must_use]
+    pub fn delimiter(&self) -> char {
+        self.delimiter
+    }
+
+    /// Return (the number of skipped lines, the number of lines not referenced by clusters)
+    #[must_use]
+    pub fn load_counts(&self) -> (usize, usize) {
+        (self.skipped, self.notfound)
+    }
+
+    /// Re-extract tokens of all loaded events from the specified feature columns.
+    ///
+    /// Return the total number of tokens after re-tokenizing.
+    pub fn retokenize(
+        &mut self,
+        features: &[(usize, ColumnType)],
+        normalizer: &dyn TokenNormalizer,
+    ) -> usize {
+        let mut count = 0;
+        let delimiter = self.delimiter;
+        let json_columns = self.json_columns.as_deref();
+        self.token_sources.clear();
+        for message in self.events.values_mut() {
+            let tokens = extract_features(
+                &message.content.fields(delimiter, json_columns),
+                features,
+                &self.token_chars,
+                normalizer,
+                self.email_tokens,
+                &mut self.token_sources,
+            );
+            count += tokens.len();
+            message.tokens = tokens;
+        }
+        count
+    }
+
+    /// Return the field at `column` of the event.
+    #[must_use]
+    pub fn field(&self, message_id: &MessageId, column: usize) -> Option<String> {
+        self.events.get(message_id).and_then(|message| {
+            message
+                .content
+                .fields(self.delimiter, self.json_columns.as_deref())
+                .into_iter()
+                .nth(column)
+        })
+    }
+
+    /// Return the characters treated as a part of tokens besides alphanumerics.
+    #[must_use]
+    pub fn token_chars(&self) -> &[char] {
+        &self.token_chars
+    }
+
+    #[must_use]
+    pub fn tokens_count(&self) -> usize {
+        self.events.values().map(|m| m.tokens.len()).sum()
+    }
+
     #[must_use]
     pub fn tokens(&self, message_id: &MessageId) -> Option<&Vec<String>> {
         self.events.get(message_id).map(|m| &m.tokens)
     }
 
+    /// Return the columns where `token` is extracted, in ascending order.
+    #[must_use]
+    pub fn token_sources(&self, token: &str) -> Vec<usize> {
+        self.token_sources
+            .get(token)
+            .map(|columns| columns.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Return the smallest id of the events having `token`.
+    #[must_use]
+    pub fn token_example(&self, token: &str) -> Option<&MessageId> {
+        self.events
+            .iter()
+            .filter(|(_, message)| message.tokens.iter().any(|t| t == token))
+            .map(|(message_id, _)| message_id)
+            .min()
+    }
+
+    /// Return the aliases of columns.
+    #[must_use]
+    pub fn aliases(&self) -> &[String] {
+        &self.aliases
+    }
+
     #[must_use]
     pub fn regex_match(&self, re: &Regex, event_ids: &[MessageId]) -> Vec<String> {
         event_ids
             .iter()
             .filter_map(|msg_id| {
                 self.events.get(msg_id).map(|event| {
-                    if re.is_match(&event.content) {
+                    if re.is_match(&event.content.as_str(self.delimiter)) {
                         Some(msg_id.to_string())
                     } else {
                         None
@@ -131,10 +398,224 @@ impl Events {
         // false
     }
 
+    /// Return the byte spans `(start, end)` matched with `re` in each matching event.
+    /// Only tested until a highlight of matches uses it.
+    #[cfg(test)]
     #[must_use]
-    pub fn get_message(&self, message_id: &MessageId) -> Option<&str> {
+    pub fn regex_match_spans(
+        &self,
+        re: &Regex,
+        event_ids: &[MessageId],
+    ) -> Vec<(MessageId, Vec<(usize, usize)>)> {
+        event_ids
+            .iter()
+            .filter_map(|msg_id| {
+                let event = self.events.get(msg_id)?;
+                let spans: Vec<_> = re
+                    .find_iter(&event.content.as_str(self.delimiter))
+                    .map(|m| (m.start(), m.end()))
+                    .collect();
+                if spans.is_empty() {
+                    None
+                } else {
+                    Some((msg_id.to_string(), spans))
+                }
+            })
+            .collect()
+    }
+
+    /// Return the event as `alias=value` pairs. `None` if the number of fields does not
+    /// match the columns in config.
+    #[must_use]
+    pub fn get_pretty_message(&self, message_id: &MessageId) -> Option<String> {
+        let fields = self
+            .events
+            .get(message_id)?
+            .content
+            .fields(self.delimiter, self.json_columns.as_deref());
+        if fields.len() != self.aliases.len() {
+            return None;
+        }
+        Some(
+            self.aliases
+                .iter()
+                .zip(fields)
+                .map(|(alias, value)| format!("{}={}", alias, value))
+                .collect::<Vec<_>>()
+                .join(" "),
+        )
+    }
+
+    /// Return the event without its key to compare the contents of events.
+    #[must_use]
+    pub fn content_without_key(&self, message_id: &MessageId) -> Option<String> {
+        let message = self.events.get(message_id)?;
+        Some(if let Some(key_column) = self.key_column {
+            let mut fields = message
+                .content
+                .fields(self.delimiter, self.json_columns.as_deref());
+            if key_column < fields.len() {
+                fields.remove(key_column);
+            }
+            fields.join(&self.delimiter.to_string())
+        } else {
+            message.content.as_str(self.delimiter).into_owned()
+        })
+    }
+
+    #[must_use]
+    pub fn get_message(&self, message_id: &MessageId) -> Option<Cow<'_, str>> {
         self.events
             .get(message_id)
-            .map(|message| message.content.as_str())
+            .map(|message| message.content.as_str(self.delimiter))
+    }
+}
+
+/// Events joined with their continuation lines, which do not match `record_start`.
+/// Every line is an event if `record_start` is not set.
+struct Records<I: Iterator<Item = String>> {
+    lines: Peekable<I>,
+    record_start: Option<Regex>,
+}
+
+impl<I: Iterator<Item = String>> Iterator for Records<I> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let mut record = self.lines.next()?;
+        if let Some(re) = &self.record_start {
+            while let Some(line) = self.lines.next_if(|line| !re.is_match(line)) {
+                record.push('\n');
+                record.push_str(&line);
+            }
+        }
+        Some(record)
+    }
+}
+
+/// The key of the `idx`-th event which does not match `key_regex`.
+fn synthetic_key(idx: usize) -> MessageId {
+    format!("#{}", idx + 1)
+}
+
+/// Return the values of `columns` in the json object. Missing fields are empty strings.
+fn json_fields(line: &str, columns: &[String]) -> Option<Vec<String>> {
+    let obj: serde_json::Map<String, Value> = serde_json::from_str(line).ok()?;
+    Some(
+        columns
+            .iter()
+            .map(|alias| match obj.get(alias) {
+                Some(Value::String(s)) => s.clone(),
+                Some(Value::Null) | None => String::new(),
+                Some(v) => v.to_string(),
+            })
+            .collect(),
+    )
+}
+
+fn extract_features<S: AsRef<str>>(
+    log: &[S],
+    features: &[(usize, ColumnType)],
+    token_chars: &[char],
+    normalizer: &dyn TokenNormalizer,
+    email_tokens: EmailTokens,
+    sources: &mut HashMap<String, BTreeSet<usize>>,
+) -> Vec<String> {
+    let mut tokens = Vec::new();
+    for (feature_idx, data_type) in features {
+        if let Some(value) = log.get(*feature_idx) {
+            let start = tokens.len();
+            tokens.extend(parser::extract_column_tokens(
+                value.as_ref(),
+                *data_type,
+                token_chars,
+                normalizer,
+            ));
+            if email_tokens != EmailTokens::Off {
+                tokens.extend(parser::extract_emails(
+                    value.as_ref(),
+                    email_tokens == EmailTokens::WithDomain,
+                ));
+            }
+            for token in &tokens[start..] {
+                if let Some(columns) = sources.get_mut(token) {
+                    columns.insert(*feature_idx);
+                } else {
+                    sources.insert(token.clone(), BTreeSet::from([*feature_idx]));
+                }
+            }
+        }
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(lines: &[(&str, &str)]) -> Events {
+        Events {
+            events: lines
+                .iter()
+                .map(|(id, line)| {
+                    let message = Message {
+                        _id: (*id).to_string(),
+                        content: Content::Line((*line).to_string()),
+                        tokens: Vec::new(),
+                    };
+                    ((*id).to_string(), message)
+                })
+                .collect(),
+            delimiter: ',',
+            ..Events::default()
+        }
+    }
+
+    #[test]
+    fn regex_match_spans_of_events() {
+        let events = events(&[
+            ("e1", "e1,GET /a.php?id=1,10.0.0.1"),
+            ("e2", "e2,POST /login.php,10.0.0.2"),
+            ("e3", "e3,GET /b.php?id=2&id=3,10.0.0.3"),
+        ]);
+        let re = Regex::new(r"id=\d").expect("valid regex");
+        let ids: Vec<MessageId> = ["e1", "e2", "e3", "e4"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            events.regex_match_spans(&re, &ids),
+            vec![
+                (String::from("e1"), vec![(14, 18)]),
+                (String::from("e3"), vec![(14, 18), (19, 23)]),
+            ]
+        );
+        assert_eq!(events.regex_match(&re, &ids), vec!["e1", "e3"]);
+    }
+
+    #[test]
+    fn skip_lines_not_utf8() {
+        let dir = std::env::temp_dir().join(format!("labeler-{}-events", std::process::id()));
+        std::fs::create_dir_all(dir.join("tidb")).expect("writable temp dir");
+        let files: [(&str, &[u8]); 3] = [
+            (
+                "events.csv",
+                b"e1,GET /a.php\ne2,GET /\xff.php\ne3,GET /b.php\n",
+            ),
+            ("clusters.json", b"{}"),
+            ("labels.json", b"{}"),
+        ];
+        for (file, content) in &files {
+            std::fs::write(dir.join(file), content).expect("writable temp dir");
+        }
+        let events = Config::from_dir(&dir.display().to_string()).and_then(|cfg| {
+            let ids = ["e1", "e2", "e3"].iter().map(ToString::to_string).collect();
+            Events::new(&cfg, ids)
+        });
+        std::fs::remove_dir_all(&dir).expect("removable temp dir");
+        let events = events.expect("valid events");
+        assert_eq!(events.len(), 2);
+        assert!(events.contains(&String::from("e3")));
+        assert_eq!(events.load_counts(), (1, 0));
     }
 }
@@ -1,7 +1,12 @@
 use crate::{config::Load, ClusterId, MessageId, PatternId, RuleId, Score, TidbId};
-use anyhow::Result;
-use serde::Deserialize;
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
 use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
 
 type RepresentativeLabels = Vec<(ClusterId, Vec<(TidbId, RuleId, usize, Score)>)>;
 type EventLabels = Vec<(ClusterId, Vec<(MessageId, Vec<(TidbId, RuleId, Score)>)>)>;
@@ -18,21 +23,111 @@ struct DebugLabels {
 
 impl Load for DebugLabels {}
 
+impl DebugLabels {
+    /// Load the labels skipping malformed cluster entries of `representative` and `events`.
+    ///
+    /// Return the labels and the number of skipped entries.
+    fn from_path_skipping_invalid(path: &str) -> Result<(Self, usize)> {
+        let file = File::open(path)?;
+        // the error of serde_json tells the line and column
+        let value: Value = serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| anyhow!("invalid labels file {}: {}", path, e))?;
+        let mut skipped = 0;
+        let representative = valid_entries(&value, "representative", &mut skipped);
+        let events = valid_entries(&value, "events", &mut skipped);
+        let count = |key| {
+            value
+                .get(key)
+                .and_then(Value::as_u64)
+                .and_then(|n| usize::try_from(n).ok())
+                .unwrap_or_default()
+        };
+        Ok((
+            DebugLabels {
+                representative_labels: count("representative_labels"),
+                event_labels: count("event_labels"),
+                representative,
+                events,
+            },
+            skipped,
+        ))
+    }
+}
+
+/// Return the entries of the array `key` in `value` which are deserialized successfully.
+/// The malformed entries are warned with their index, and counted in `skipped`.
+fn valid_entries<T: DeserializeOwned>(value: &Value, key: &str, skipped: &mut usize) -> Vec<T> {
+    let entries = match value.get(key).and_then(Value::as_array) {
+        Some(entries) => entries,
+        None => {
+            warn!("labels: {} not found", key);
+            return Vec::new();
+        }
+    };
+    entries
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, entry)| match T::deserialize(entry) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                warn!("labels: {}[{}] skipped. {}", key, idx, e);
+                *skipped += 1;
+                None
+            }
+        })
+        .collect()
+}
+
 pub struct Labels {
     clusters_labels_map: HashMap<ClusterId, Vec<PatternId>>,
     clusters_events_map: ClusterByEvents,
     labels_clusters_map: HashMap<PatternId, Vec<ClusterId>>,
+    labeled_clusters: Vec<ClusterId>, // sorted clusters of all labels
+    tidb_clusters_map: HashMap<TidbId, Vec<ClusterId>>, // sorted clusters of all rules in tidb
+    rule_clusters_map: HashMap<RuleId, Vec<ClusterId>>, // sorted clusters of rule in all tidbs
     representative: RepresentativeLabels,
     events: EventLabels,
 }
 
 impl Labels {
+    /// Malformed cluster entries are skipped with a warning if the labels file cannot be
+    /// loaded as a whole.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the file cannot be opened or it is not json.
     pub fn new(path: &str) -> Result<Self> {
-        let debug_labels = DebugLabels::from_path(path)?;
+        let debug_labels = match DebugLabels::from_path(path) {
+            Ok(labels) => labels,
+            Err(e) => {
+                warn!("{:#}", e);
+                let (labels, skipped) = DebugLabels::from_path_skipping_invalid(path)?;
+                let msg = format!("{} malformed entries of {} are skipped.", skipped, path);
+                warn!("{}", msg);
+                eprintln!("Warning: {}", msg);
+                labels
+            }
+        };
+        let mut labels = Self {
+            clusters_labels_map: HashMap::new(),
+            clusters_events_map: HashMap::new(),
+            labels_clusters_map: HashMap::new(),
+            labeled_clusters: Vec::new(),
+            tidb_clusters_map: HashMap::new(),
+            rule_clusters_map: HashMap::new(),
+            representative: debug_labels.representative,
+            events: debug_labels.events,
+        };
+        labels.index();
+        Ok(labels)
+    }
+
+    /// Build the maps between clusters, events and labels from `events`.
+    fn index(&mut self) {
         let mut clusters_labels_map: HashMap<ClusterId, Vec<PatternId>> = HashMap::new();
         let mut clusters_events_map: ClusterByEvents = HashMap::new();
         let mut labels_clusters_map: HashMap<PatternId, Vec<ClusterId>> = HashMap::new();
-        for (cluster_id, events) in &debug_labels.events {
+        for (cluster_id, events) in &self.events {
             clusters_events_map
                 .entry(*cluster_id)
                 .or_insert_with(|| events.clone());
@@ -50,24 +145,89 @@ impl Labels {
             }
         }
         for patterns in clusters_labels_map.values_mut() {
-            patterns.sort_by(|a, b| {
-                let aa = (u64::from(a.0) << 32) | u64::from(a.1);
-                let bb = (u64::from(b.0) << 32) | u64::from(b.1);
-                aa.cmp(&bb)
-            });
+            patterns.sort_by_key(|p| pattern_sort_key(*p));
             patterns.dedup();
         }
         for clusters in labels_clusters_map.values_mut() {
             clusters.sort_unstable();
+            clusters.dedup();
         }
 
-        Ok(Self {
-            clusters_labels_map,
-            clusters_events_map,
-            labels_clusters_map,
-            representative: debug_labels.representative,
-            events: debug_labels.events,
-        })
+        // wildcard queries of `find_clusters` are computed here once. these are rebuilt
+        // whenever labels are modified. (see `merge`)
+        let mut labeled_clusters: Vec<ClusterId> = clusters_labels_map.keys().copied().collect();
+        labeled_clusters.sort_unstable();
+        let mut tidb_clusters_map: HashMap<TidbId, Vec<ClusterId>> = HashMap::new();
+        let mut rule_clusters_map: HashMap<RuleId, Vec<ClusterId>> = HashMap::new();
+        for ((tidb_id, rule_id), clusters) in &labels_clusters_map {
+            tidb_clusters_map
+                .entry(*tidb_id)
+                .or_default()
+                .extend(clusters);
+            rule_clusters_map
+                .entry(*rule_id)
+                .or_default()
+                .extend(clusters);
+        }
+        for clusters in tidb_clusters_map
+            .values_mut()
+            .chain(rule_clusters_map.values_mut())
+        {
+            clusters.sort_unstable();
+            clusters.dedup();
+        }
+
+        self.clusters_labels_map = clusters_labels_map;
+        self.clusters_events_map = clusters_events_map;
+        self.labels_clusters_map = labels_clusters_map;
+        self.labeled_clusters = labeled_clusters;
+        self.tidb_clusters_map = tidb_clusters_map;
+        self.rule_clusters_map = rule_clusters_map;
+    }
+
+    /// Move the labels of `sources` clusters to `target`. The counts and scores of the same
+    /// representative labels are added up.
+    pub fn merge(&mut self, target: ClusterId, sources: &[ClusterId]) {
+        let mut merged_events = Vec::new();
+        self.events.retain_mut(|(cid, events)| {
+            if sources.contains(cid) {
+                merged_events.append(events);
+                false
+            } else {
+                true
+            }
+        });
+        if !merged_events.is_empty() {
+            if let Some((_, events)) = self.events.iter_mut().find(|(cid, _)| *cid == target) {
+                events.append(&mut merged_events);
+            } else {
+                self.events.push((target, merged_events));
+            }
+        }
+
+        let mut merged_labels: Vec<(TidbId, RuleId, usize, Score)> = Vec::new();
+        self.representative.retain(|(cid, labels)| {
+            if *cid == target || sources.contains(cid) {
+                for (tidb_id, rule_id, count, score) in labels {
+                    if let Some(label) = merged_labels
+                        .iter_mut()
+                        .find(|(t, r, _, _)| (*t, *r) == (*tidb_id, *rule_id))
+                    {
+                        label.2 += count;
+                        label.3 += score;
+                    } else {
+                        merged_labels.push((*tidb_id, *rule_id, *count, *score));
+                    }
+                }
+                false
+            } else {
+                true
+            }
+        });
+        if !merged_labels.is_empty() {
+            self.representative.push((target, merged_labels));
+        }
+        self.index();
     }
 
     pub fn get_representative_labels(
@@ -98,11 +258,7 @@ impl Labels {
             }
         }
         let mut patterns: Vec<_> = patterns.into_iter().collect();
-        patterns.sort_by(|a, b| {
-            let aa = (u64::from(a.0 .0) << 32) | u64::from(a.0 .1);
-            let bb = (u64::from(b.0 .0) << 32) | u64::from(b.0 .1);
-            aa.cmp(&bb)
-        });
+        patterns.sort_by_key(|(p, _)| pattern_sort_key(*p));
         if patterns.is_empty() {
             None
         } else {
@@ -130,21 +286,126 @@ impl Labels {
         (labeled_clusters, labeled_events.len(), representatives)
     }
 
+    /// Return the sorted clusters labeled by the pattern. 0 of `tidb_id` or `rule_id` matches any.
     pub fn find_clusters(&self, tidb_id: TidbId, rule_id: RuleId) -> Vec<ClusterId> {
-        let mut found = Vec::new();
-        for (pattern_id, clusters) in &self.labels_clusters_map {
-            if (pattern_id.0 == tidb_id || tidb_id == 0)
-                && (pattern_id.1 == rule_id || rule_id == 0)
-            {
-                found.extend(clusters);
-            }
-        }
-        found.sort_unstable();
-        found.dedup();
-        found
+        let found = match (tidb_id, rule_id) {
+            (0, 0) => return self.labeled_clusters.clone(),
+            (_, 0) => self.tidb_clusters_map.get(&tidb_id),
+            (0, _) => self.rule_clusters_map.get(&rule_id),
+            _ => self.labels_clusters_map.get(&(tidb_id, rule_id)),
+        };
+        found.cloned().unwrap_or_default()
+    }
+
+    /// Return the number of labeled events in the cluster.
+    pub fn labeled_events_count(&self, cluster_id: ClusterId) -> usize {
+        self.clusters_events_map
+            .get(&cluster_id)
+            .map_or(0, Vec::len)
+    }
+
+    /// Return the number of events in the cluster labeled by the pattern.
+    /// 0 of `TidbId` or `RuleId` matches any id.
+    pub fn events_count_of(&self, cluster_id: ClusterId, pattern: PatternId) -> usize {
+        let (tidb_id, rule_id) = pattern;
+        self.clusters_events_map
+            .get(&cluster_id)
+            .map_or(0, |events| {
+                events
+                    .iter()
+                    .filter(|(_, labels)| {
+                        labels.iter().any(|(t, r, _)| {
+                            (*t == tidb_id || tidb_id == 0) && (*r == rule_id || rule_id == 0)
+                        })
+                    })
+                    .count()
+            })
+    }
+
+    /// Return the events in the cluster labeled by the pattern.
+    pub fn events_of(&self, cluster_id: ClusterId, pattern: PatternId) -> Vec<&MessageId> {
+        self.clusters_events_map
+            .get(&cluster_id)
+            .map(|events| {
+                events
+                    .iter()
+                    .filter(|(_, labels)| {
+                        labels
+                            .iter()
+                            .any(|(tidb_id, rule_id, _)| (*tidb_id, *rule_id) == pattern)
+                    })
+                    .map(|(message_id, _)| message_id)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn labels_of(&self, cluster_id: ClusterId) -> HashSet<PatternId> {
+        self.clusters_labels_map
+            .get(&cluster_id)
+            .map(|labels| labels.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Return the number of distinct labels of the cluster.
+    pub fn label_count(&self, cluster_id: ClusterId) -> usize {
+        self.clusters_labels_map
+            .get(&cluster_id)
+            .map_or(0, Vec::len)
     }
 
     pub fn is_labeled(&self, cluster_id: ClusterId) -> bool {
         self.clusters_labels_map.contains_key(&cluster_id)
     }
 }
+
+/// Order of pattern ids, by tidb id and then by rule id.
+fn pattern_sort_key((tidb_id, rule_id): PatternId) -> u64 {
+    (u64::from(tidb_id) << 32) | u64::from(rule_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Labels of clusters having one event each: (cluster id, patterns of the event)
+    fn labels(clusters: &[(ClusterId, &[PatternId])]) -> Labels {
+        let events = clusters
+            .iter()
+            .map(|(cid, patterns)| {
+                let patterns = patterns.iter().map(|(t, r)| (*t, *r, 1.0)).collect();
+                (*cid, vec![(format!("e{}", cid), patterns)])
+            })
+            .collect();
+        let mut labels = Labels {
+            clusters_labels_map: HashMap::new(),
+            clusters_events_map: HashMap::new(),
+            labels_clusters_map: HashMap::new(),
+            labeled_clusters: Vec::new(),
+            tidb_clusters_map: HashMap::new(),
+            rule_clusters_map: HashMap::new(),
+            representative: Vec::new(),
+            events,
+        };
+        labels.index();
+        labels
+    }
+
+    #[test]
+    fn find_clusters_of_rule_in_any_tidb() {
+        let labels = labels(&[(1, &[(5, 12)]), (2, &[(7, 12), (7, 3)]), (3, &[(5, 3)])]);
+        assert_eq!(labels.find_clusters(0, 12), vec![1, 2]);
+        assert_eq!(labels.find_clusters(5, 0), vec![1, 3]);
+        assert_eq!(labels.find_clusters(7, 3), vec![2]);
+        assert_eq!(labels.find_clusters(0, 0), vec![1, 2, 3]);
+        assert!(labels.find_clusters(0, 99).is_empty());
+    }
+
+    #[test]
+    fn pattern_order() {
+        assert!(pattern_sort_key((1, 2)) < pattern_sort_key((1, 10)));
+        assert!(pattern_sort_key((1, u32::MAX)) < pattern_sort_key((2, 0)));
+        assert!(pattern_sort_key((2, 1)) > pattern_sort_key((1, 100)));
+        assert_eq!(pattern_sort_key((3, 4)), pattern_sort_key((3, 4)));
+    }
+}
@@ -6,10 +6,12 @@ pub mod matcher;
 mod parser;
 mod tidb;
 
-use ansi_term::Colour;
+use ansi_term::{Colour, Style};
 use anyhow::Result;
 use num_derive::{FromPrimitive, ToPrimitive};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use strum::EnumIter;
 
 pub type ClusterId = usize;
@@ -19,22 +21,18 @@ pub type RuleId = u32;
 pub type PatternId = (TidbId, RuleId);
 pub type MessageId = String;
 pub type TokensVector = Vec<(MessageId, Vec<String>, Vec<String>)>;
+pub type Filter = (FilterType, FilterOp, Option<String>); // arguments of `/filter`
 
 /* Datasource data type */
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum EventType {
+    #[default]
     Csv,
     Log,
     Packet,
 }
 
-impl Default for EventType {
-    fn default() -> Self {
-        EventType::Csv
-    }
-}
-
 impl std::fmt::Display for EventType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -57,45 +55,118 @@ impl std::str::FromStr for EventType {
     }
 }
 
+static COLOR_ENABLED: AtomicBool = AtomicBool::new(true);
+static LIGHT_THEME: AtomicBool = AtomicBool::new(false);
+
+/// Color scheme of the output
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Theme {
+    #[default]
+    Dark,
+    Light,
+    None,
+}
+
+impl std::str::FromStr for Theme {
+    type Err = ();
+    fn from_str(input: &str) -> Result<Theme, Self::Err> {
+        match input {
+            "dark" => Ok(Theme::Dark),
+            "light" => Ok(Theme::Light),
+            "none" => Ok(Theme::None),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Set the color scheme. `Theme::None` disables colors.
+pub fn set_theme(theme: Theme) {
+    LIGHT_THEME.store(theme == Theme::Light, Ordering::Relaxed);
+    if theme == Theme::None {
+        set_color(false);
+    }
+}
+
+/// Return the color of the current theme for `c` of the dark theme.
+#[must_use]
+pub fn themed(c: Colour) -> Colour {
+    if !LIGHT_THEME.load(Ordering::Relaxed) {
+        return c;
+    }
+    match c {
+        Colour::Green => Colour::Fixed(28),
+        Colour::Red => Colour::Fixed(124),
+        Colour::Blue => Colour::Fixed(19),
+        Colour::Yellow => Colour::Fixed(130),
+        _ => c,
+    }
+}
+
+/// Enable or disable ANSI colors and styles of the output.
+pub fn set_color(enabled: bool) {
+    COLOR_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+#[must_use]
+pub fn is_color_enabled() -> bool {
+    COLOR_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Return `s` if colors are enabled, otherwise the plain style.
+#[must_use]
+pub fn style(s: Style) -> Style {
+    style_if(is_color_enabled(), s)
+}
+
+/// Return `s` if `color` is set, otherwise the plain style, regardless of `set_color`.
+#[must_use]
+pub fn style_if(color: bool, s: Style) -> Style {
+    if color {
+        s
+    } else {
+        Style::new()
+    }
+}
+
 #[macro_export]
 macro_rules! bold {
     ($x:expr) => {
-        ansi_term::Style::new().bold().paint($x)
+        $crate::style(ansi_term::Style::new().bold()).paint($x)
     };
 }
 
 #[macro_export]
 macro_rules! ubold {
     ($x:expr) => {
-        Style::new().bold().underline().paint($x)
+        $crate::style(ansi_term::Style::new().bold().underline()).paint($x)
     };
 }
 
 #[macro_export]
 macro_rules! blue {
     ($x:expr) => {
-        Colour::Blue.paint($x)
+        $crate::style($crate::themed(ansi_term::Colour::Blue).normal()).paint($x)
     };
 }
 
 #[macro_export]
 macro_rules! red {
     ($x:expr) => {
-        Colour::Red.paint($x)
+        $crate::style($crate::themed(ansi_term::Colour::Red).normal()).paint($x)
     };
 }
 
 #[macro_export]
 macro_rules! boldgreen {
     ($x:expr) => {
-        Colour::Green.bold().paint($x)
+        $crate::style($crate::themed(ansi_term::Colour::Green).bold()).paint($x)
     };
 }
 
 #[macro_export]
 macro_rules! boldred {
     ($x:expr) => {
-        Colour::Red.bold().paint($x)
+        $crate::style($crate::themed(ansi_term::Colour::Red).bold()).paint($x)
     };
 }
 
@@ -109,10 +180,25 @@ macro_rules! hashmap {
 }
 
 #[derive(
-    Debug, Clone, Copy, EnumIter, Eq, FromPrimitive, ToPrimitive, Hash, Ord, PartialOrd, PartialEq,
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    EnumIter,
+    Eq,
+    FromPrimitive,
+    ToPrimitive,
+    Hash,
+    Ord,
+    PartialOrd,
+    PartialEq,
+    Serialize,
+    Deserialize,
 )]
+#[serde(rename_all = "lowercase")]
 pub enum Qualifier {
     Benign = 1,
+    #[default]
     Unknown = 2,
     Suspicious = 3,
     Mixed = 4,
@@ -148,12 +234,6 @@ pub fn qualifiers_header() -> Vec<String> {
     ORDERED_QUALIFIERS.iter().map(ToString::to_string).collect()
 }
 
-impl Default for Qualifier {
-    fn default() -> Self {
-        Qualifier::Unknown
-    }
-}
-
 impl std::str::FromStr for Qualifier {
     type Err = ();
     fn from_str(value: &str) -> Result<Qualifier, Self::Err> {
@@ -167,31 +247,44 @@ impl std::str::FromStr for Qualifier {
     }
 }
 
+impl std::convert::TryFrom<u8> for Qualifier {
+    type Error = anyhow::Error;
+    fn try_from(value: u8) -> Result<Qualifier, Self::Error> {
+        num_traits::FromPrimitive::from_u8(value)
+            .ok_or_else(|| anyhow::anyhow!("invalid qualifier {}", value))
+    }
+}
+
 impl std::fmt::Display for Qualifier {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Qualifier::Benign => write!(f, "{}", boldgreen!("benign")),
-            Qualifier::Unknown => write!(f, "unknown"),
-            Qualifier::Suspicious => write!(f, "{}", boldred!("suspicious")),
-            Qualifier::Mixed => write!(f, "mixed"),
-        }
+        write!(f, "{}", self.paint(is_color_enabled()))
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+impl Qualifier {
+    /// Return the name of the qualifier, in colors if `color` is set.
+    #[must_use]
+    pub fn paint(self, color: bool) -> String {
+        let (name, s) = match self {
+            Qualifier::Benign => ("benign", themed(Colour::Green).bold()),
+            Qualifier::Unknown => ("unknown", Style::new()),
+            Qualifier::Suspicious => ("suspicious", themed(Colour::Red).bold()),
+            Qualifier::Mixed => ("mixed", Style::new()),
+        };
+        style_if(color, s).paint(name).to_string()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub enum FilterOp {
     L,
     LE,
     G,
     GE,
+    #[default]
     EQ,
     NE,
 }
-impl Default for FilterOp {
-    fn default() -> Self {
-        FilterOp::EQ
-    }
-}
 
 impl std::fmt::Display for FilterOp {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -221,29 +314,29 @@ impl std::str::FromStr for FilterOp {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
 pub enum FilterType {
+    #[default]
     NoFilter,
+    And,
     Auto,
     Count,
     IPaddr,
     Label,
+    LabelAll,
+    LabelCoverage,
+    LabelCount,
     Qualifier,
     Regex,
     Score,
     LabelScore,
     Sort,
     Status,
+    Tag,
     Time,
     Token,
 }
 
-impl Default for FilterType {
-    fn default() -> Self {
-        FilterType::NoFilter
-    }
-}
-
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SortType {
     Alphabet,
@@ -257,33 +350,149 @@ impl std::fmt::Display for SortType {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Divisor of the representative label scores
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LabelScoreNorm {
+    #[default]
+    BySize,
+    ByLabeledEvents,
+    Raw,
+}
+
+impl std::str::FromStr for LabelScoreNorm {
+    type Err = ();
+    fn from_str(input: &str) -> Result<LabelScoreNorm, Self::Err> {
+        match input {
+            "size" => Ok(LabelScoreNorm::BySize),
+            "labeled" => Ok(LabelScoreNorm::ByLabeledEvents),
+            "raw" => Ok(LabelScoreNorm::Raw),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How the signature of cluster is shown
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "serde_json::Value")]
+pub enum SignatureDisplay {
+    Off,
+    #[default]
+    Truncated,
+    Full,
+}
+
+impl std::str::FromStr for SignatureDisplay {
+    type Err = ();
+    fn from_str(input: &str) -> Result<SignatureDisplay, Self::Err> {
+        match input {
+            "off" => Ok(SignatureDisplay::Off),
+            "on" | "truncated" => Ok(SignatureDisplay::Truncated),
+            "full" => Ok(SignatureDisplay::Full),
+            _ => Err(()),
+        }
+    }
+}
+
+// `Signature(bool)` of the settings saved by older versions is read as `Truncated` or `Off`.
+impl std::convert::TryFrom<serde_json::Value> for SignatureDisplay {
+    type Error = String;
+    fn try_from(value: serde_json::Value) -> Result<SignatureDisplay, Self::Error> {
+        match value {
+            serde_json::Value::Bool(true) => Ok(SignatureDisplay::Truncated),
+            serde_json::Value::Bool(false) => Ok(SignatureDisplay::Off),
+            serde_json::Value::String(s) => match s.as_str() {
+                "Off" => Ok(SignatureDisplay::Off),
+                "Truncated" => Ok(SignatureDisplay::Truncated),
+                "Full" => Ok(SignatureDisplay::Full),
+                _ => Err(format!("invalid signature display {}", s)),
+            },
+            v => Err(format!("invalid signature display {}", v)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum ConfigType {
+    Dedup(bool),
+    FastNav(bool),
+    Highlight(bool),
+    LabelDescription(bool),
+    LabelScoreNorm(LabelScoreNorm),
+    LineWidth(usize),
+    PrettySamples(bool),
+    RegexCase(bool),
+    SampleNumber(bool),
     SamplesCount(usize),
+    ScoreDigits(usize),
+    ScoreEpsilon(Score),
     Reverse(bool),
     Samples(bool),
-    Signature(bool),
+    SamplesTail(bool),
+    Signature(SignatureDisplay),
     Tokens(bool),
 }
 
+impl ConfigType {
+    /// Clamp the value into its valid range. At least one sample is shown.
+    #[must_use]
+    pub fn clamped(self) -> Self {
+        match self {
+            ConfigType::SamplesCount(count) => ConfigType::SamplesCount(count.max(1)),
+            x => x,
+        }
+    }
+}
+
+/// Display settings of cli. These are saved per dataset. (see `CliConf::save`)
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct CliConf {
+    pub dedup: ConfigType,
+    pub fast_nav: ConfigType,
+    pub highlight: ConfigType,
+    pub label_description: ConfigType,
+    pub label_score_norm: ConfigType,
+    pub line_width: ConfigType,
+    pub pretty_samples: ConfigType,
+    pub regex_case: ConfigType,
+    pub sample_number: ConfigType,
     pub samples_count: ConfigType,
+    pub score_digits: ConfigType,
+    pub score_epsilon: ConfigType,
     pub csv_fields: Vec<usize>,
     pub show_samples: ConfigType,
+    pub samples_tail: ConfigType,
     pub reverse: ConfigType,
     pub show_signature: ConfigType,
     pub show_tokens: ConfigType,
 }
 const DEFAULT_SAMPLES_DISPLAY_COUNT: usize = 30;
+const DEFAULT_SCORE_DIGITS: usize = 3;
+const DEFAULT_LINE_WIDTH: usize = 500;
+const DEFAULT_SCORE_EPSILON: Score = 1e-3;
+/// `samples_count` to show all events of a cluster
+pub const SAMPLES_COUNT_ALL: usize = usize::MAX;
 
 impl Default for CliConf {
     fn default() -> Self {
         CliConf {
+            dedup: ConfigType::Dedup(false),
+            fast_nav: ConfigType::FastNav(false),
+            highlight: ConfigType::Highlight(false),
+            label_description: ConfigType::LabelDescription(false),
+            label_score_norm: ConfigType::LabelScoreNorm(LabelScoreNorm::default()),
+            line_width: ConfigType::LineWidth(DEFAULT_LINE_WIDTH),
+            pretty_samples: ConfigType::PrettySamples(false),
+            regex_case: ConfigType::RegexCase(true),
+            sample_number: ConfigType::SampleNumber(false),
             samples_count: ConfigType::SamplesCount(DEFAULT_SAMPLES_DISPLAY_COUNT),
+            score_digits: ConfigType::ScoreDigits(DEFAULT_SCORE_DIGITS),
+            score_epsilon: ConfigType::ScoreEpsilon(DEFAULT_SCORE_EPSILON),
             csv_fields: Vec::new(),
             show_samples: ConfigType::Samples(true),
+            samples_tail: ConfigType::SamplesTail(false),
             reverse: ConfigType::Reverse(false),
-            show_signature: ConfigType::Signature(true),
+            show_signature: ConfigType::Signature(SignatureDisplay::default()),
             show_tokens: ConfigType::Tokens(true),
         }
     }
@@ -291,19 +500,100 @@ impl Default for CliConf {
 
 impl CliConf {
     fn samples_count(&self) -> usize {
+        // settings saved by old versions may have 0
         if let ConfigType::SamplesCount(count) = self.samples_count {
-            count
+            count.max(1)
         } else {
             DEFAULT_SAMPLES_DISPLAY_COUNT
         }
     }
 
+    /// The number of decimal digits of cluster scores to display
+    #[must_use]
+    pub fn score_digits(&self) -> usize {
+        if let ConfigType::ScoreDigits(digits) = self.score_digits {
+            digits
+        } else {
+            DEFAULT_SCORE_DIGITS
+        }
+    }
+
+    /// The maximum length in bytes of a sample to display
+    fn line_width(&self) -> usize {
+        if let ConfigType::LineWidth(width) = self.line_width {
+            width
+        } else {
+            DEFAULT_LINE_WIDTH
+        }
+    }
+
+    /// Tolerance of `=` and `<>` comparison of scores
+    #[must_use]
+    pub fn score_epsilon(&self) -> Score {
+        if let ConfigType::ScoreEpsilon(eps) = self.score_epsilon {
+            eps
+        } else {
+            DEFAULT_SCORE_EPSILON
+        }
+    }
+
+    #[must_use]
+    pub fn is_fast_nav_on(&self) -> bool {
+        self.fast_nav == ConfigType::FastNav(true)
+    }
+
+    #[must_use]
+    pub fn label_score_norm(&self) -> LabelScoreNorm {
+        if let ConfigType::LabelScoreNorm(norm) = self.label_score_norm {
+            norm
+        } else {
+            LabelScoreNorm::default()
+        }
+    }
+
+    /// Whether regular expressions of `/filter regex` and `/event regex` are case sensitive
+    #[must_use]
+    pub fn is_regex_case_on(&self) -> bool {
+        self.regex_case == ConfigType::RegexCase(true)
+    }
+
+    fn is_highlight_on(&self) -> bool {
+        self.highlight == ConfigType::Highlight(true)
+    }
+
+    fn is_label_description_on(&self) -> bool {
+        self.label_description == ConfigType::LabelDescription(true)
+    }
+
+    fn is_sample_number_on(&self) -> bool {
+        self.sample_number == ConfigType::SampleNumber(true)
+    }
+
+    /// Whether identical events are shown once with the number of them
+    fn is_dedup_on(&self) -> bool {
+        self.dedup == ConfigType::Dedup(true)
+    }
+
+    /// Whether samples are shown as `alias=value` pairs
+    fn is_pretty_samples_on(&self) -> bool {
+        self.pretty_samples == ConfigType::PrettySamples(true)
+    }
+
     fn is_show_samples_on(&self) -> bool {
         self.show_samples == ConfigType::Samples(true)
     }
 
-    fn is_show_signature_on(&self) -> bool {
-        self.show_signature == ConfigType::Signature(true)
+    /// Whether samples are shown from the last event
+    fn is_samples_tail_on(&self) -> bool {
+        self.samples_tail == ConfigType::SamplesTail(true)
+    }
+
+    fn signature_display(&self) -> SignatureDisplay {
+        if let ConfigType::Signature(display) = self.show_signature {
+            display
+        } else {
+            SignatureDisplay::default()
+        }
     }
 
     #[must_use]
@@ -311,13 +601,84 @@ impl CliConf {
         self.reverse == ConfigType::Reverse(true)
     }
 
+    /// Load the settings saved for `key` in `path`. Defaults if not found.
+    #[must_use]
+    pub fn load(path: &str, key: &str) -> Self {
+        load_settings(path)
+            .remove(key)
+            .and_then(|v| serde_json::from_value(v).ok())
+            .unwrap_or_default()
+    }
+
+    /// Save the settings for `key` in `path`, keeping the settings of other keys.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if it fails to write `path`.
+    pub fn save(&self, path: &str, key: &str) -> Result<()> {
+        let mut all = load_settings(path);
+        all.insert(key.to_string(), serde_json::to_value(self)?);
+        std::fs::write(path, serde_json::to_string_pretty(&all)?)?;
+        Ok(())
+    }
+
     pub fn set(&mut self, x: ConfigType) {
+        let x = x.clamped();
         match x {
+            ConfigType::Dedup(_) => self.dedup = x,
+            ConfigType::FastNav(_) => self.fast_nav = x,
+            ConfigType::Highlight(_) => self.highlight = x,
+            ConfigType::LabelDescription(_) => self.label_description = x,
+            ConfigType::LabelScoreNorm(_) => self.label_score_norm = x,
+            ConfigType::LineWidth(_) => self.line_width = x,
+            ConfigType::PrettySamples(_) => self.pretty_samples = x,
+            ConfigType::RegexCase(_) => self.regex_case = x,
+            ConfigType::SampleNumber(_) => self.sample_number = x,
             ConfigType::SamplesCount(_) => self.samples_count = x,
+            ConfigType::ScoreDigits(_) => self.score_digits = x,
+            ConfigType::ScoreEpsilon(_) => self.score_epsilon = x,
             ConfigType::Reverse(_) => self.reverse = x,
             ConfigType::Samples(_) => self.show_samples = x,
+            ConfigType::SamplesTail(_) => self.samples_tail = x,
             ConfigType::Signature(_) => self.show_signature = x,
             ConfigType::Tokens(_) => self.show_tokens = x,
         }
     }
 }
+
+/// Read the settings of all datasets. (key -> `CliConf`)
+fn load_settings(path: &str) -> HashMap<String, serde_json::Value> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_traits::ToPrimitive;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn qualifier_round_trip() {
+        for q in ORDERED_QUALIFIERS {
+            let n = q.to_u8().expect("qualifier fits in u8");
+            assert_eq!(Qualifier::try_from(n).ok(), Some(q));
+        }
+        assert!(Qualifier::try_from(0).is_err());
+        assert!(Qualifier::try_from(5).is_err());
+    }
+
+    #[test]
+    fn samples_count_is_at_least_one() {
+        let mut conf = CliConf::default();
+        conf.set(ConfigType::SamplesCount(0));
+        assert_eq!(conf.samples_count, ConfigType::SamplesCount(1));
+        assert_eq!(conf.samples_count(), 1);
+
+        let loaded: CliConf = serde_json::from_str(r#"{"samples_count":{"SamplesCount":0}}"#)
+            .expect("valid settings");
+        assert_eq!(loaded.samples_count(), 1);
+    }
+}
@@ -1,65 +1,160 @@
 use ansi_term::Style;
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use labeler::{
-    config::Config, matcher::TitleMatch, CliConf, ClusterId, ConfigType, FilterOp, FilterType,
-    Qualifier,
+    config::Config,
+    matcher::{parse_label_coverage, parse_pattern_ids, PushResult, TitleMatch},
+    CliConf, ClusterId, ConfigType, Filter, FilterOp, FilterType, LabelScoreNorm, Qualifier, Score,
+    SignatureDisplay, SAMPLES_COUNT_ALL,
 };
-use log::{error, info};
+use log::{error, info, warn};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use rustyline::{config::Configurer, error::ReadlineError};
-use rustyline_derive::{Helper, Highlighter, Hinter, Validator};
-use std::{collections::LinkedList, str::FromStr};
+use rustyline_derive::{Helper, Highlighter, Validator};
+use std::{
+    cell::RefCell,
+    collections::LinkedList,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, IsTerminal},
+    path::Path,
+    rc::Rc,
+    str::FromStr,
+    time::{Duration, Instant},
+};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 struct Opt {
-    #[structopt(short, long)]
-    config_path: String,
+    #[structopt(short, long, required_unless = "config-dir")]
+    config_path: Option<String>,
+    /// directory which has clusters.json, labels.json, events.csv and tidb/
+    #[structopt(long, conflicts_with = "config-path")]
+    config_dir: Option<String>,
+    /// disable ANSI colors. colors are disabled if stdout is not a terminal.
+    #[structopt(long)]
+    no_color: bool,
+    /// color scheme of the output
+    #[structopt(long, default_value = "dark", possible_values = &["dark", "light", "none"])]
+    theme: String,
+    /// run the commands in the file non-interactively
+    #[structopt(long)]
+    script: Option<String>,
+    /// stop the script at the first failed command
+    #[structopt(long, requires = "script")]
+    abort_on_error: bool,
+    /// start with empty clusters or events instead of exiting
+    #[structopt(long)]
+    allow_empty: bool,
+    /// print the statistics in json and exit. stdout has only the json line
+    #[structopt(long, conflicts_with = "script")]
+    json: bool,
+    /// print the time taken by each loading stage
+    #[structopt(long)]
+    timings: bool,
+    /// directory for history, settings, saved qualifiers, dumps and exports.
+    /// the directory of the config file is used if not set.
+    #[structopt(long)]
+    output_dir: Option<String>,
 }
 
 fn main() {
     env_logger::init();
     let opt = Opt::from_args();
-    let cfg = Config::init(&opt.config_path);
+    labeler::set_color(!opt.no_color && std::io::stdout().is_terminal());
+    labeler::set_theme(opt.theme.parse().unwrap_or_default());
+    let cfg = match (&opt.config_path, &opt.config_dir) {
+        (Some(path), _) => Config::init(path),
+        (None, Some(dir)) => Config::init_from_dir(dir),
+        (None, None) => unreachable!(),
+    };
 
-    if let Err(e) = run(&cfg) {
+    if let Err(e) = run(&cfg, &opt) {
         error!("{:#}", e);
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CliCmd {
+    Bookmark,
+    Bookmarks,
     ClusterID,
+    Compare,
     Event(FilterType, FilterOp),
     Exit,
+    Explain,
+    Dump,
+    ExportEvents,
+    ExportLabels,
+    ExportNdjson,
+    ExportScript,
     Filter(FilterType, FilterOp),
+    GoBookmark,
     GoNext,
     GoPrev,
+    GoScore(bool),
     Help,
     Jump,
+    Merge(bool),
+    Preview(FilterType, FilterOp),
     QuitProgram,
+    Redo,
+    Related,
+    Resume,
     Save(bool),
     Set(ConfigType),
+    SetDelimiter,
+    SetProfile,
     SetQualifier(bool),
+    Stats,
     Status,
+    Tag(bool),
+    Tidb,
+    Token,
     Undefined,
 }
 
-#[derive(Helper, Hinter, Highlighter, Validator)]
+#[derive(Helper, Highlighter, Validator)]
 struct CmdCompleter {
     commands: Vec<&'static str>,
+    champion: Rc<RefCell<TitleMatch>>, // to hint the summary of cluster while typing `#<id>`
+    score_digits: usize,
 }
 const CMDLIST: &[&str] = &[
+    "/bookmark",
+    "/bookmarks",
+    "/compare",
+    "/dump",
     "/event regex",
     "/event clear",
+    "/explain",
+    "/export events",
+    "/export labels",
+    "/export ndjson",
+    "/export script",
+    "/filter and",
     "/filter count",
     "/filter label",
+    "/filter label <>",
+    "/filter label-all",
+    "/filter labelcount",
+    "/filter labelcov",
     "/filter qualifier benign",
     "/filter qualifier mixed",
     "/filter qualifier suspicious",
     "/filter qualifier unknown",
     "/filter regex",
     "/filter score",
+    "/filter tag",
+    "/goto bookmark",
     "/help",
+    "/jump last",
+    "/jump random",
+    "/merge signatures",
+    "/merge signatures apply",
+    "/next score",
+    "/prev score",
+    "/preview",
+    "/related",
+    "/resume",
     "/quit",
     "/save",
     "/save force",
@@ -67,22 +162,58 @@ const CMDLIST: &[&str] = &[
     "/set benign all",
     "/set csvstyle off",
     "/set csvstyle on",
+    "/set dedup off",
+    "/set dedup on",
+    "/set delimiter",
+    "/set fastnav off",
+    "/set fastnav on",
+    "/set highlight off",
+    "/set highlight on",
+    "/set labeldesc off",
+    "/set labeldesc on",
+    "/set labelnorm labeled",
+    "/set labelnorm raw",
+    "/set labelnorm size",
+    "/set linewidth",
     "/set mixed",
     "/set mixed all",
+    "/set prettysamples off",
+    "/set prettysamples on",
+    "/set profile",
+    "/set regexcase off",
+    "/set regexcase on",
     "/set reverse off",
     "/set reverse on",
+    "/set samplenum off",
+    "/set samplenum on",
+    "/set samples head",
     "/set samples off",
     "/set samples on",
+    "/set samples tail",
+    "/set samplescount all",
+    "/set scoredigits",
+    "/set scoreeps",
+    "/set signature full",
     "/set signature off",
     "/set signature on",
+    "/set signature truncated",
     "/set suspicious",
     "/set suspicious all",
     "/set tokens off",
     "/set tokens on",
     "/set unknown",
     "/set unknown all",
+    "/stats labels",
+    "/stats signatures",
+    "/stats sizes",
     "/status",
+    "/status tidb",
+    "/tag add",
+    "/tag remove",
+    "/tidb list",
+    "/token info",
     "/x",
+    "/x redo",
 ];
 
 impl rustyline::completion::Completer for CmdCompleter {
@@ -108,141 +239,726 @@ impl rustyline::completion::Completer for CmdCompleter {
     }
 }
 
+impl rustyline::hint::Hinter for CmdCompleter {
+    fn hint(&self, line: &str, pos: usize, _ctx: &rustyline::Context<'_>) -> Option<String> {
+        if pos < line.len() {
+            return None;
+        }
+        let cid = line.strip_prefix('#')?.parse::<ClusterId>().ok()?;
+        let champion = self.champion.try_borrow().ok()?;
+        champion
+            .cluster_summary(cid, self.score_digits)
+            .map(|s| format!("  {}", s))
+    }
+}
+
 const COMMAND_HISTORY_FILE: &str = ".cli_history.txt";
+const CLI_SETTINGS_FILE: &str = ".cli_settings.json";
+const FAST_NAV_INTERVAL: Duration = Duration::from_millis(300);
+
+/// The state of the command loop shared by the interactive and the script mode.
+struct Session {
+    prompt: LinkedList<(String, Option<usize>)>,
+    title: String,
+    ticks: Option<usize>,
+    clicfg: CliConf,
+    rng: StdRng,
+    last_nav: Option<Instant>,
+    bookmarks: Vec<ClusterId>,
+    settings_key: Option<String>, // save `clicfg` for this dataset on `/set` if set
+    output_dir: Option<String>,
+    redo: Vec<(String, Option<usize>)>, // prompts of the layers exited by `/x`
+    merge_listing: Option<Vec<Vec<ClusterId>>>, // groups listed by `/merge signatures` to apply
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session {
+            prompt: LinkedList::new(),
+            title: String::from("Clusters"),
+            ticks: None,
+            clicfg: CliConf::default(),
+            rng: StdRng::from_entropy(),
+            last_nav: None,
+            bookmarks: Vec::new(),
+            settings_key: None,
+            output_dir: None,
+            redo: Vec::new(),
+            merge_listing: None,
+        }
+    }
+}
+
+impl Session {
+    /// Return the path of the generated file `name` in the output directory.
+    /// `name` is returned as is if it is absolute or the output directory is not set.
+    fn output_path(&self, name: &str) -> String {
+        match &self.output_dir {
+            Some(dir) if !Path::new(name).is_absolute() => {
+                Path::new(dir).join(name).display().to_string()
+            }
+            _ => name.to_string(),
+        }
+    }
+
+    /// Update the prompt for the filter layer added by `/filter` with the arguments `opt`.
+    fn enter_layer(&mut self, t: FilterType, op: FilterOp, opt: Option<String>) {
+        self.prompt.push_back((self.title.to_string(), self.ticks));
+        self.redo.clear();
+        if let Some(s) = opt {
+            self.title = format!("{}({:?} {} {})", self.title, t, op, s);
+        } else if t == FilterType::Label {
+            self.title = format!("{}({:?} {} All)", self.title, t, op);
+        }
+        self.ticks = None;
+    }
+}
+
+/// What to do after a command is dispatched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    Show,
+    Skip,
+    Quit,
+    Confirm,      // the regex layer matching all clusters is not added. ask to add it anyway
+    ConfirmMerge, // the clusters listed by `/merge signatures` are not merged yet. ask to merge
+}
 
 /// # Errors
 ///
 /// Will return `Err` if database connection failed or labeldb_* tables are not exist in database.
-#[allow(clippy::too_many_lines)]
-fn run(cfg: &Config) -> Result<()> {
-    let mut champion = TitleMatch::new(cfg)?;
-    let mut limit = champion.count_clusters();
+fn run(cfg: &Config, opt: &Opt) -> Result<()> {
+    let output_dir = output_dir(opt);
+    if let Some(dir) = &output_dir {
+        std::fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir))?;
+    }
+    let mut champion = TitleMatch::new(cfg, opt.allow_empty, opt.timings)?;
+    if opt.json {
+        println!("{}", serde_json::to_string(&champion.statistics())?);
+        return Ok(());
+    }
     champion.show_statistics();
 
+    if let Some(path) = &opt.script {
+        let session = Session {
+            output_dir,
+            ..Session::default()
+        };
+        return run_script(cfg, &mut champion, session, path, opt.abort_on_error);
+    }
+
+    let champion = Rc::new(RefCell::new(champion));
     let mut rl = rustyline::Editor::<CmdCompleter>::new();
     let completer = CmdCompleter {
         commands: CMDLIST.to_vec(),
+        champion: Rc::clone(&champion),
+        score_digits: 0,
     };
     rl.set_helper(Some(completer));
     rl.set_completion_type(rustyline::CompletionType::List);
-    let _r = rl.load_history(COMMAND_HISTORY_FILE);
 
-    let mut prompt: LinkedList<(String, Option<usize>, usize)> = LinkedList::new();
-    let style = Style::new().reverse();
-    let mut title: String = String::from("Clusters");
-    let mut tag: String;
-    let mut ticks: Option<usize> = None;
-    let mut clicfg = CliConf::default();
+    let style = labeler::style(Style::new().reverse());
+    let mut session = Session {
+        output_dir,
+        ..Session::default()
+    };
+    let history = session.output_path(COMMAND_HISTORY_FILE);
+    let _r = rl.load_history(&history);
+    let key = settings_key(opt);
+    session.clicfg = CliConf::load(&session.output_path(CLI_SETTINGS_FILE), &key);
+    session.settings_key = Some(key);
 
     loop {
-        tag = if ticks.is_none() {
-            format!("\n{} [{}]# ", style.paint(&title), limit)
+        if let Some(helper) = rl.helper_mut() {
+            helper.score_digits = session.clicfg.score_digits();
+        }
+        let limit = champion.borrow().layer_len();
+        let tag = if let Some(v) = session.ticks {
+            format!("\n{} [{}/{}]# ", style.paint(&session.title), v + 1, limit)
         } else {
-            format!(
-                "\n{} [{}/{}]# ",
-                style.paint(&title),
-                ticks.unwrap_or(0) + 1,
-                limit
-            )
+            format!("\n{} [{}]# ", style.paint(&session.title), limit)
         };
         let (cmdtype, opt) = get_user_input(&mut rl, &tag);
-        info!("Command: {:?}, option: {:?}", cmdtype, opt);
-        match cmdtype {
-            CliCmd::ClusterID => {
-                if let Some(s) = opt {
-                    if let Ok(cid) = s.parse::<ClusterId>() {
-                        ticks = champion.find_cluster(cid);
-                    }
+        let flow = dispatch(
+            cfg,
+            &mut champion.borrow_mut(),
+            &mut session,
+            cmdtype,
+            opt.clone(),
+        );
+        match flow {
+            Ok(Flow::Quit) => break,
+            Ok(Flow::Confirm) => {
+                let confirmed = matches!(
+                    rl.readline("Add the layer anyway? [y/N] ")
+                        .as_deref()
+                        .map(str::trim),
+                    Ok("y" | "Y")
+                );
+                if confirmed {
+                    push_confirmed(&mut champion.borrow_mut(), &mut session, cmdtype, opt);
                 }
             }
-            CliCmd::Event(t, _) => {
-                do_event_filtering(&mut champion, t, opt.as_deref(), &ticks);
+            Ok(Flow::ConfirmMerge) => {
+                let confirmed = matches!(
+                    rl.readline("Merge them? [y/N] ").as_deref().map(str::trim),
+                    Ok("y" | "Y")
+                );
+                if confirmed {
+                    merge_confirmed(&mut champion.borrow_mut(), &mut session);
+                }
             }
-            CliCmd::Exit => {
-                if !prompt.is_empty() {
-                    if champion.remove_filter().is_ok() {
-                        let t = prompt.pop_back().unwrap();
-                        title = t.0;
-                        ticks = t.1;
-                        limit = t.2;
-                    } else {
-                        println!("Error: failed to exit layers.");
+            Ok(_) => {}
+            Err(e) => println!("Error: {}\n", e),
+        }
+    }
+
+    let unsaved = champion.borrow().unsaved_count();
+    if unsaved > 0 {
+        let save = cfg.autosave_on_exit() || {
+            let question = format!(
+                "You have {} unsaved changes. Save before exiting? [y/N] ",
+                unsaved
+            );
+            matches!(
+                rl.readline(&question).as_deref().map(str::trim),
+                Ok("y" | "Y")
+            )
+        };
+        if save {
+            let path = session.output_path(cfg.output_qualifiers());
+            let cnt = champion.borrow_mut().save(&path, true)?;
+            println!("{} qualifiers saved to {}", cnt, path);
+        }
+    }
+
+    champion.borrow().show_session_summary();
+    // losing the command history is not an error of the session
+    match rl.save_history(&history) {
+        Ok(()) => info!("command history saved to {}", history),
+        Err(e) => {
+            let msg = format!("failed to save command history to {}: {}", history, e);
+            warn!("{}", msg);
+            eprintln!("Warning: {}", msg);
+        }
+    }
+    Ok(())
+}
+
+/// Push the regex layer matching all clusters, which `/filter` of `cmdtype` and `opt` left
+/// to be confirmed by `Flow::Confirm`.
+fn push_confirmed(
+    champion: &mut TitleMatch,
+    session: &mut Session,
+    cmdtype: CliCmd,
+    opt: Option<String>,
+) {
+    if let (CliCmd::Filter(t, op), Some(cnt)) = (cmdtype, champion.push_pending()) {
+        session.enter_layer(t, op, opt);
+        println!("Matched clusters = {}\n", cnt);
+    }
+}
+
+/// Merge the clusters of the same signature, which `/merge signatures apply` left to be
+/// confirmed by `Flow::ConfirmMerge`. The merged clusters are removed from the bookmarks.
+fn merge_confirmed(champion: &mut TitleMatch, session: &mut Session) {
+    session.merge_listing = None;
+    let (groups, removed, cleared) = champion.merge_signatures();
+    session.redo.clear();
+    session.bookmarks.retain(|cid| !removed.contains(cid));
+    println!(
+        "{} clusters are removed by merging into {} clusters",
+        removed.len(),
+        groups
+    );
+    if cleared > 0 {
+        println!("the event filters of {} clusters are cleared", cleared);
+    }
+    println!();
+    if !removed.is_empty() {
+        session.ticks = None;
+    }
+}
+
+/// The output directory set by `--output-dir`, or the directory of the config file.
+/// `None` if the config file is in the current directory.
+fn output_dir(opt: &Opt) -> Option<String> {
+    if opt.output_dir.is_some() {
+        return opt.output_dir.clone();
+    }
+    let dir = match (&opt.config_path, &opt.config_dir) {
+        (Some(path), _) => Path::new(path).parent()?,
+        (None, Some(dir)) => Path::new(dir),
+        (None, None) => return None,
+    };
+    if dir.as_os_str().is_empty() {
+        None
+    } else {
+        Some(dir.display().to_string())
+    }
+}
+
+/// The settings of cli are saved by the absolute path of config file or directory.
+fn settings_key(opt: &Opt) -> String {
+    let path = opt
+        .config_path
+        .as_deref()
+        .or(opt.config_dir.as_deref())
+        .unwrap_or_default();
+    std::fs::canonicalize(path).map_or_else(|_| path.to_string(), |p| p.display().to_string())
+}
+
+/// Run the commands in the script file line by line. Empty lines are ignored.
+/// A regex layer matching all clusters is added, and `/merge signatures apply` merges the
+/// clusters listed by `/merge signatures`, without confirmation.
+///
+/// # Errors
+///
+/// Will return `Err` if the script file cannot be read, or if a command fails and
+/// `abort_on_error` is set.
+fn run_script(
+    cfg: &Config,
+    champion: &mut TitleMatch,
+    mut session: Session,
+    path: &str,
+    abort_on_error: bool,
+) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("cannot open {}", path))?;
+    for (idx, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        println!("{}:{}> {}", path, idx + 1, line.trim());
+        let (cmdtype, opt) = parse_command(&line);
+        match dispatch(cfg, champion, &mut session, cmdtype, opt.clone()) {
+            Ok(Flow::Quit) => break,
+            // the layers confirmed interactively are replayed. e.g. by `/export script`
+            Ok(Flow::Confirm) => push_confirmed(champion, &mut session, cmdtype, opt),
+            Ok(Flow::ConfirmMerge) => merge_confirmed(champion, &mut session),
+            Ok(_) => {}
+            Err(e) => {
+                if abort_on_error {
+                    return Err(anyhow!("{}:{}: {}", path, idx + 1, e));
+                }
+                eprintln!("Error: {}:{}: {}", path, idx + 1, e);
+            }
+        }
+    }
+
+    if cfg.autosave_on_exit() && champion.unsaved_count() > 0 {
+        let path = session.output_path(cfg.output_qualifiers());
+        let cnt = champion.save(&path, true)?;
+        println!("{} qualifiers saved to {}", cnt, path);
+    }
+    Ok(())
+}
+
+/// Execute a command and print the current cluster if needed.
+///
+/// # Errors
+///
+/// Will return `Err` if the command is undefined or fails.
+fn dispatch(
+    cfg: &Config,
+    champion: &mut TitleMatch,
+    session: &mut Session,
+    cmdtype: CliCmd,
+    opt: Option<String>,
+) -> Result<Flow> {
+    info!("Command: {:?}, option: {:?}", cmdtype, opt);
+    let flow = match cmdtype {
+        CliCmd::Bookmark => {
+            let cid = session
+                .ticks
+                .and_then(|v| champion.cluster_id(v))
+                .ok_or_else(|| anyhow!("no cluster selected."))?;
+            if session.bookmarks.contains(&cid) {
+                println!("cluster #{} is already bookmarked.\n", cid);
+            } else {
+                session.bookmarks.push(cid);
+                println!("bookmark [{}] cluster #{}\n", session.bookmarks.len(), cid);
+            }
+            Flow::Skip
+        }
+        CliCmd::Bookmarks => {
+            for (i, cid) in session.bookmarks.iter().enumerate() {
+                if let Some(idx) = champion.find_cluster(*cid) {
+                    println!("[{}] cluster #{} at {}", i + 1, cid, idx + 1);
+                } else {
+                    println!("[{}] cluster #{} (not in this layer)", i + 1, cid);
+                }
+            }
+            Flow::Skip
+        }
+        CliCmd::GoBookmark => {
+            let cid = opt
+                .and_then(|s| s.parse::<usize>().ok())
+                .and_then(|n| session.bookmarks.get(n.wrapping_sub(1)))
+                .copied()
+                .ok_or_else(|| anyhow!("bookmark not found."))?;
+            session.ticks = Some(
+                champion
+                    .find_cluster(cid)
+                    .ok_or_else(|| anyhow!("cluster #{} is not in this layer.", cid))?,
+            );
+            Flow::Show
+        }
+        CliCmd::ClusterID => {
+            if let Some(s) = opt {
+                if let Ok(cid) = s.parse::<ClusterId>() {
+                    session.ticks = champion.find_cluster(cid);
+                }
+            }
+            Flow::Show
+        }
+        CliCmd::Compare => {
+            if let Some(s) = opt {
+                let ids: Vec<ClusterId> = s
+                    .split_whitespace()
+                    .filter_map(|id| id.trim_start_matches('#').parse::<ClusterId>().ok())
+                    .collect();
+                if let [a, b] = ids[..] {
+                    champion.compare(a, b)?;
+                }
+            }
+            Flow::Skip
+        }
+        CliCmd::Event(t, _) => {
+            do_event_filtering(champion, &session.clicfg, t, opt.as_deref(), &session.ticks);
+            Flow::Show
+        }
+        CliCmd::Explain => {
+            if let (Some(s), Some(v)) = (opt, session.ticks) {
+                if let Some(cid) = champion.cluster_id(v) {
+                    if let Some(pattern) = parse_pattern_ids(&s).first() {
+                        champion.explain_label(cid, *pattern)?;
                     }
                 }
-                continue;
-            }
-            CliCmd::Filter(t, op) => {
-                if let Some(len) = do_filtering(&mut champion, t, op, opt.as_deref()) {
-                    prompt.push_back((title.to_string(), ticks, limit));
-                    if let Some(s) = opt {
-                        title = format!("{}({:?} {} {})", title, t, op, s);
-                    } else if t == FilterType::Label {
-                        title = format!("{}({:?} {} All)", title, t, op);
+            }
+            Flow::Skip
+        }
+        CliCmd::Exit => {
+            if !session.prompt.is_empty() {
+                champion
+                    .remove_filter()
+                    .map_err(|_| anyhow!("failed to exit layers."))?;
+                session
+                    .redo
+                    .push((session.title.to_string(), session.ticks));
+                if let Some((title, ticks)) = session.prompt.pop_back() {
+                    session.title = title;
+                    session.ticks = ticks;
+                }
+            }
+            Flow::Skip
+        }
+        CliCmd::Redo => {
+            // pop the session entry only if the filter layer is restored
+            match champion.restore_filter() {
+                Some(cnt) => {
+                    session
+                        .prompt
+                        .push_back((session.title.to_string(), session.ticks));
+                    if let Some((title, ticks)) = session.redo.pop() {
+                        session.title = title;
+                        session.ticks = ticks;
                     }
-                    limit = len;
-                    ticks = None;
+                    println!("Matched clusters = {}\n", cnt);
+                }
+                None if champion.is_layers_full() => {
+                    println!("Too many filter layers. Use /x to exit from the current layer.\n");
                 }
-                continue;
+                None => {
+                    session.redo.clear();
+                    println!("No filter layer to redo.\n");
+                }
+            }
+            Flow::Skip
+        }
+        CliCmd::Dump => {
+            if let (Some(path), Some(v)) = (opt, session.ticks) {
+                let path = session.output_path(&path);
+                champion.dump_cluster(v, &path, &session.clicfg)?;
+                println!("cluster dumped to {}\n", path);
+            }
+            Flow::Skip
+        }
+        CliCmd::ExportEvents => {
+            if let (Some(path), Some(v)) = (opt, session.ticks) {
+                let path = session.output_path(&path);
+                let cnt = champion.export_events(v, &path)?;
+                println!("{} events exported to {}\n", cnt, path);
+            }
+            Flow::Skip
+        }
+        CliCmd::ExportLabels => {
+            if let Some(path) = opt {
+                let path = session.output_path(&path);
+                let cnt = champion.export_labels(&path, &session.clicfg)?;
+                println!("{} labels exported to {}\n", cnt, path);
+            }
+            Flow::Skip
+        }
+        CliCmd::ExportNdjson => {
+            if let Some(path) = opt {
+                let path = session.output_path(&path);
+                let file =
+                    File::create(&path).with_context(|| format!("cannot create {}", path))?;
+                let cnt = champion.export_ndjson(&mut BufWriter::new(file))?;
+                println!("{} clusters exported to {}\n", cnt, path);
+            } else {
+                // only the json lines are written to stdout to pipe them
+                let cnt = champion.export_ndjson(&mut io::stdout().lock())?;
+                eprintln!("{} clusters exported", cnt);
             }
-            CliCmd::GoNext | CliCmd::GoPrev => {
-                ticks = Some(do_goto(cmdtype, ticks, clicfg.is_reverse_on()));
+            Flow::Skip
+        }
+        CliCmd::Filter(t, op) => {
+            match do_filtering(champion, &session.clicfg, t, op, opt.as_deref()) {
+                PushResult::Pushed(_) => {
+                    session.enter_layer(t, op, opt);
+                    Flow::Skip
+                }
+                PushResult::NeedsConfirm => Flow::Confirm,
+                _ => Flow::Skip,
             }
-            CliCmd::Help => {
-                show_help();
-                continue;
+        }
+        CliCmd::GoNext | CliCmd::GoPrev => {
+            session.ticks = Some(do_goto(
+                cmdtype,
+                session.ticks,
+                session.clicfg.is_reverse_on(),
+            ));
+            Flow::Show
+        }
+        CliCmd::GoScore(higher) => {
+            let from = session.ticks.unwrap_or_default();
+            if let Some(idx) = champion.neighbor_by_score(from, higher) {
+                session.ticks = Some(idx);
+                Flow::Show
+            } else {
+                println!(
+                    "No cluster with {} score.\n",
+                    if higher { "higher" } else { "lower" }
+                );
+                Flow::Skip
             }
-            CliCmd::Jump => {
-                if let Some(s) = opt {
+        }
+        CliCmd::Help => {
+            show_help();
+            Flow::Skip
+        }
+        CliCmd::Jump => {
+            let limit = champion.layer_len();
+            match opt.as_deref() {
+                Some("last") => session.ticks = Some(limit.saturating_sub(1)),
+                Some("random") if limit > 0 => {
+                    session.ticks = Some(session.rng.gen_range(0..limit));
+                }
+                Some(s) => {
                     if let Ok(i) = s.parse::<usize>() {
                         if i > 0 {
-                            ticks = Some(i - 1);
+                            session.ticks = Some(i - 1);
+                        }
+                    }
+                }
+                None => {}
+            }
+            Flow::Show
+        }
+        CliCmd::Preview(t, op) => {
+            let cnt = if t == FilterType::And {
+                opt.as_deref()
+                    .and_then(parse_filters)
+                    .map_or(0, |filters| champion.preview_all(&filters, &session.clicfg))
+            } else {
+                champion.preview(t, op, opt.as_deref(), &session.clicfg)
+            };
+            println!("{} clusters would be matched.\n", cnt);
+            Flow::Skip
+        }
+        CliCmd::QuitProgram => Flow::Quit,
+        CliCmd::Related => {
+            if let Some(cid) = session.ticks.and_then(|v| champion.cluster_id(v)) {
+                let related = champion.related_by_top_label(cid);
+                for rid in &related {
+                    if let Some(summary) =
+                        champion.cluster_summary(*rid, session.clicfg.score_digits())
+                    {
+                        if let Some(idx) = champion.find_cluster(*rid) {
+                            println!("{} at {}", summary, idx + 1);
+                        } else {
+                            println!("{} (not in this layer)", summary);
                         }
                     }
                 }
+                println!(
+                    "{} clusters share the top label of cluster #{}\n",
+                    related.len(),
+                    cid
+                );
             }
-            CliCmd::QuitProgram => break,
-            // CliCmd::Save(_) => {
-            //     /* save qualifiers and labels */
-            //     // let _ = champion.cli_save(cfg);
-            //     continue;
-            // }
-            CliCmd::Set(x) => {
-                clicfg.set(x);
+            Flow::Skip
+        }
+        CliCmd::Set(x) => {
+            let x = x.clamped();
+            session.clicfg.set(x);
+            if x == ConfigType::SamplesCount(SAMPLES_COUNT_ALL) {
+                println!("set SamplesCount(all)\n");
+            } else {
                 println!("set {:?}\n", x);
-                continue;
             }
-            CliCmd::SetQualifier(x) => {
-                if let Some(s) = opt {
-                    if let Some(v) = ticks {
-                        champion.set_qualifier(v, &s, x);
-                    }
+            if let Some(key) = &session.settings_key {
+                session
+                    .clicfg
+                    .save(&session.output_path(CLI_SETTINGS_FILE), key)?;
+            }
+            Flow::Skip
+        }
+        CliCmd::SetDelimiter => {
+            if let Some(c) = opt.and_then(|s| s.chars().next()) {
+                champion.set_delimiter(cfg, c)?;
+            }
+            Flow::Skip
+        }
+        CliCmd::SetProfile => {
+            if let Some(s) = opt {
+                champion.set_profile(cfg, &s)?;
+            }
+            Flow::Skip
+        }
+        CliCmd::SetQualifier(x) => {
+            if let Some(s) = opt {
+                if let Some(v) = session.ticks {
+                    champion.set_qualifier(v, &s, x);
                 }
             }
-            CliCmd::Save(_) | CliCmd::Status => {
-                // champion.print_statistics();
-                continue;
+            Flow::Show
+        }
+        CliCmd::Merge(false) => {
+            champion.show_signature_merges();
+            let (merges, _) = champion.signature_merges();
+            if !merges.is_empty() {
+                println!("Use /merge signatures apply to merge them.");
+            }
+            println!();
+            session.merge_listing = Some(merges);
+            Flow::Skip
+        }
+        CliCmd::Merge(true) => {
+            let (merges, _) = champion.signature_merges();
+            if session.merge_listing.as_ref() != Some(&merges) {
+                println!("Use /merge signatures to list the clusters to merge first.\n");
+                Flow::Skip
+            } else if merges.is_empty() {
+                println!("No clusters to merge.\n");
+                Flow::Skip
+            } else {
+                Flow::ConfirmMerge
+            }
+        }
+        CliCmd::ExportScript => {
+            if let Some(path) = opt {
+                let path = session.output_path(&path);
+                let cnt = champion.export_script(&path)?;
+                println!("{} filters exported to {}\n", cnt, path);
             }
-            CliCmd::Undefined => {
-                println!("Undefined command!\n");
-                continue;
+            Flow::Skip
+        }
+        CliCmd::Resume => {
+            let filter = CliCmd::Filter(FilterType::Qualifier, FilterOp::EQ);
+            dispatch(
+                cfg,
+                champion,
+                session,
+                filter,
+                Some(String::from("unknown")),
+            )?;
+            // jump to the first cluster even if no layer was added
+            session.ticks = Some(0);
+            Flow::Show
+        }
+        CliCmd::Stats => {
+            let reverse = session.clicfg.is_reverse_on();
+            match opt.as_deref() {
+                Some("labels") => champion.show_label_frequency(reverse),
+                Some("signatures") => champion.show_signature_groups(reverse),
+                Some("sizes") => champion.show_size_histogram(reverse),
+                _ => {}
             }
+            Flow::Skip
         }
+        CliCmd::Save(force) => {
+            let path = session.output_path(cfg.output_qualifiers());
+            let cnt = champion.save(&path, force)?;
+            println!("{} qualifiers saved to {}\n", cnt, path);
+            Flow::Skip
+        }
+        CliCmd::Status => {
+            if opt.as_deref() == Some("tidb") {
+                champion.show_tidb_status();
+            } else {
+                champion.show_status();
+            }
+            Flow::Skip
+        }
+        CliCmd::Tag(add) => {
+            if let Some(tag) = opt {
+                if let Some(v) = session.ticks {
+                    champion.set_tag(v, &tag, add);
+                }
+            }
+            Flow::Show
+        }
+        CliCmd::Tidb => {
+            champion.show_tidb_list();
+            Flow::Skip
+        }
+        CliCmd::Token => {
+            if let Some(word) = opt {
+                champion.show_token_info(&word);
+            }
+            Flow::Skip
+        }
+        // `opt` tells why the arguments of a known command are rejected
+        CliCmd::Undefined => {
+            return Err(anyhow!(
+                opt.unwrap_or_else(|| String::from("Undefined command!"))
+            ))
+        }
+    };
 
-        if let Some(v) = ticks {
+    if flow == Flow::Show {
+        let limit = champion.layer_len();
+        if limit == 0 {
+            session.ticks = None;
+        } else if let Some(v) = session.ticks {
             if v >= limit {
-                ticks = Some(limit - 1);
+                session.ticks = Some(limit.saturating_sub(1));
             }
         } else {
-            ticks = Some(0);
+            session.ticks = Some(0);
         }
 
-        if let Some(v) = ticks {
-            champion.print_cluster(v, &clicfg);
+        if let Some(v) = session.ticks {
+            // show only the summary while navigating fast by holding the enter key
+            let now = Instant::now();
+            let navigating = matches!(cmdtype, CliCmd::GoNext | CliCmd::GoPrev);
+            let fast = navigating
+                && session.clicfg.is_fast_nav_on()
+                && session
+                    .last_nav
+                    .is_some_and(|t| now.duration_since(t) < FAST_NAV_INTERVAL);
+            session.last_nav = if navigating { Some(now) } else { None };
+            if fast {
+                champion.print_cluster_summary(v);
+            } else {
+                champion.print_cluster(v, &session.clicfg);
+            }
         }
     }
-
-    rl.save_history(COMMAND_HISTORY_FILE)?;
-    Ok(())
+    Ok(flow)
 }
 
 fn do_goto(cmd: CliCmd, ticks: Option<usize>, reverse: bool) -> usize {
@@ -261,13 +977,21 @@ fn do_goto(cmd: CliCmd, ticks: Option<usize>, reverse: bool) -> usize {
 
 fn do_event_filtering(
     champion: &mut TitleMatch,
+    clicfg: &CliConf,
     ft: FilterType,
     pattern: Option<&str>,
     ticks: &Option<usize>,
 ) {
     match ft {
-        FilterType::NoFilter | FilterType::Regex => {
-            let _r = champion.filter_event(ft, pattern, ticks);
+        FilterType::NoFilter => {
+            if ticks.is_some_and(|v| champion.clear_event_filter(v)) {
+                println!("event filter cleared");
+            } else {
+                println!("no active event filter");
+            }
+        }
+        FilterType::Regex => {
+            let _r = champion.filter_event(ft, pattern, ticks, clicfg);
         }
         _ => {}
     }
@@ -275,16 +999,26 @@ fn do_event_filtering(
 
 fn do_filtering(
     champion: &mut TitleMatch,
+    clicfg: &CliConf,
     ft: FilterType,
     op: FilterOp,
     pattern: Option<&str>,
-) -> Option<usize> {
-    let len = match ft {
-        FilterType::Count | FilterType::Qualifier | FilterType::Score => {
+) -> PushResult {
+    let full = "Too many filter layers. Use /x to exit from the current layer.\n";
+    if champion.is_layers_full() {
+        println!("{}", full);
+        return PushResult::Full;
+    }
+    let result = match ft {
+        FilterType::Count
+        | FilterType::Qualifier
+        | FilterType::Regex
+        | FilterType::Score
+        | FilterType::Tag => {
             if let Some(s) = pattern {
-                champion.filter_by(ft, op, s)
+                champion.filter_by(ft, op, s, clicfg)
             } else {
-                None
+                PushResult::Empty
             }
         }
         FilterType::Label => {
@@ -294,39 +1028,59 @@ fn do_filtering(
                 champion.filter_by_label(ft, op, None)
             }
         }
-        FilterType::Regex => {
+        FilterType::LabelAll => {
             if let Some(s) = pattern {
-                champion.filter_by_regex(s)
+                champion.filter_by_labels_intersection(&parse_pattern_ids(s))
             } else {
-                None
+                PushResult::Empty
+            }
+        }
+        FilterType::LabelCoverage => {
+            if let Some((label, frac)) = pattern.and_then(parse_label_coverage) {
+                champion.filter_by_label_coverage(label, op, frac, clicfg)
+            } else {
+                PushResult::Empty
             }
         }
-        _ => None,
+        FilterType::LabelCount => {
+            if let Some(n) = pattern.and_then(|s| s.parse::<usize>().ok()) {
+                champion.filter_by_label_count(op, n)
+            } else {
+                PushResult::Empty
+            }
+        }
+        FilterType::And => match (pattern, pattern.and_then(parse_filters)) {
+            (Some(s), Some(filters)) => champion.filter_by_all(&filters, s, clicfg),
+            _ => PushResult::Empty,
+        },
+        _ => PushResult::Empty,
     };
 
-    if let Some(l) = len {
-        println!("Matched clusters = {}\n", l);
-    } else {
-        println!("No matched clusters.\n");
+    match result {
+        PushResult::Pushed(cnt) => println!("Matched clusters = {}\n", cnt),
+        PushResult::Same => println!("filter matches the same clusters; not added\n"),
+        // the warning is printed by `push_layer`, and the caller asks to add it anyway
+        PushResult::NeedsConfirm => {}
+        PushResult::Empty => println!("No matched clusters.\n"),
+        PushResult::Full => println!("{}", full),
     }
-
-    len
+    result
 }
 
-#[allow(clippy::too_many_lines)]
 fn get_user_input(rl: &mut rustyline::Editor<CmdCompleter>, tag: &str) -> (CliCmd, Option<String>) {
     let input = rl.readline(tag);
-    let line = match input {
+    match input {
         Ok(l) => {
             rl.add_history_entry(l.as_str());
-            l
+            parse_command(&l)
         }
-        Err(ReadlineError::Interrupted | ReadlineError::Eof) => {
-            return (CliCmd::QuitProgram, None);
-        }
-        Err(_) => return (CliCmd::Undefined, None),
-    };
+        Err(ReadlineError::Interrupted | ReadlineError::Eof) => (CliCmd::QuitProgram, None),
+        Err(_) => (CliCmd::Undefined, None),
+    }
+}
 
+#[allow(clippy::too_many_lines)]
+fn parse_command(line: &str) -> (CliCmd, Option<String>) {
     let line = line.trim();
     if line.trim().is_empty() {
         return (CliCmd::GoNext, None);
@@ -340,6 +1094,26 @@ fn get_user_input(rl: &mut rustyline::Editor<CmdCompleter>, tag: &str) -> (CliCm
         }
     }
 
+    // `/filter and <filter arguments> ; <filter arguments> ...`
+    if let Some(rest) = line.strip_prefix("/filter and ") {
+        if parse_filters(rest).is_some() {
+            return (
+                CliCmd::Filter(FilterType::And, FilterOp::EQ),
+                Some(rest.trim().to_string()),
+            );
+        }
+        return (CliCmd::Undefined, None);
+    }
+
+    // `/preview` takes the same arguments as `/filter`
+    if let Some(rest) = line.strip_prefix("/preview ") {
+        return match parse_command(&format!("/filter {}", rest)) {
+            (CliCmd::Filter(t, op), opt) => (CliCmd::Preview(t, op), opt),
+            (CliCmd::Undefined, reason) => (CliCmd::Undefined, reason),
+            _ => (CliCmd::Undefined, None),
+        };
+    }
+
     if line.parse::<usize>().is_ok() {
         return (CliCmd::Jump, Some(line.to_string()));
     } else if line.starts_with('#') {
@@ -350,14 +1124,16 @@ fn get_user_input(rl: &mut rustyline::Editor<CmdCompleter>, tag: &str) -> (CliCm
         }
     }
 
-    let mut ls: Vec<&str> = line.split_whitespace().collect();
-    let pattern: String;
-    if ls.len() > 4 {
-        pattern = ls[3..].join(" ");
-        ls.resize(3, " ");
-        ls.push(&pattern);
-    }
+    let ls = split_args(line);
     match &ls[..] {
+        ["/compare", x, y] => {
+            let is_id = |v: &str| v.trim_start_matches('#').parse::<ClusterId>().is_ok();
+            if is_id(x) && is_id(y) {
+                return (CliCmd::Compare, Some(format!("{} {}", x, y)));
+            }
+        }
+        ["/bookmark"] => return (CliCmd::Bookmark, None),
+        ["/bookmarks"] => return (CliCmd::Bookmarks, None),
         ["/event", "clear"] => return (CliCmd::Event(FilterType::NoFilter, FilterOp::EQ), None),
         ["/event", "regex", x] => {
             return (
@@ -365,31 +1141,84 @@ fn get_user_input(rl: &mut rustyline::Editor<CmdCompleter>, tag: &str) -> (CliCm
                 Some((*x).to_string()),
             )
         }
+        ["/dump", x] => return (CliCmd::Dump, Some((*x).to_string())),
+        ["/export", "events", x] => return (CliCmd::ExportEvents, Some((*x).to_string())),
+        ["/export", "labels", x] => return (CliCmd::ExportLabels, Some((*x).to_string())),
+        ["/export", "ndjson"] => return (CliCmd::ExportNdjson, None),
+        ["/export", "ndjson", x] => return (CliCmd::ExportNdjson, Some((*x).to_string())),
+        ["/export", "script", x] => return (CliCmd::ExportScript, Some((*x).to_string())),
         ["/filter", "count", x, y] => {
-            if let Ok(op) = FilterOp::from_str(*x) {
+            if let Ok(op) = FilterOp::from_str(x) {
+                // out of range or negative counts are rejected instead of being taken as 0
                 if y.parse::<usize>().is_ok() {
                     return (
                         CliCmd::Filter(FilterType::Count, op),
                         Some((*y).to_string()),
                     );
                 }
+                return (CliCmd::Undefined, Some(String::from("invalid count")));
             }
         }
         ["/filter", "label"] => return (CliCmd::Filter(FilterType::Label, FilterOp::EQ), None),
+        ["/filter", "label", x @ ("=" | "<>")] => {
+            if let Ok(op) = FilterOp::from_str(x) {
+                return (CliCmd::Filter(FilterType::Label, op), None);
+            }
+        }
+        ["/filter", "label", x @ ("=" | "<>"), y] => {
+            if let Ok(op) = FilterOp::from_str(x) {
+                return (
+                    CliCmd::Filter(FilterType::Label, op),
+                    Some((*y).to_string()),
+                );
+            }
+        }
         ["/filter", "label", x] => {
             return (
                 CliCmd::Filter(FilterType::Label, FilterOp::EQ),
                 Some((*x).to_string()),
             )
         }
-        ["/filter", "qualifier", x] => {
-            if Qualifier::from_str(x).is_ok() {
-                return (
-                    CliCmd::Filter(FilterType::Qualifier, FilterOp::EQ),
-                    Some((*x).to_string()),
-                );
+        ["/filter", "label-all", x] => {
+            return (
+                CliCmd::Filter(FilterType::LabelAll, FilterOp::EQ),
+                Some((*x).to_string()),
+            )
+        }
+        ["/filter", "labelcount", x, y] => {
+            if let Ok(op) = FilterOp::from_str(x) {
+                if y.parse::<usize>().is_ok() {
+                    return (
+                        CliCmd::Filter(FilterType::LabelCount, op),
+                        Some((*y).to_string()),
+                    );
+                }
+            }
+        }
+        ["/filter", "labelcov", x, y, z] => {
+            if let Ok(op) = FilterOp::from_str(y) {
+                // NaN or infinite fractions would match nothing or everything
+                return match z.parse::<f64>() {
+                    Ok(frac) if (0.0..=1.0).contains(&frac) => (
+                        CliCmd::Filter(FilterType::LabelCoverage, op),
+                        Some(format!("{} {}", x, frac)),
+                    ),
+                    _ => (CliCmd::Undefined, Some(String::from("invalid fraction"))),
+                };
             }
         }
+        ["/filter", "tag", x] => {
+            return (
+                CliCmd::Filter(FilterType::Tag, FilterOp::EQ),
+                Some((*x).to_string()),
+            )
+        }
+        ["/filter", "qualifier", x] if Qualifier::from_str(x).is_ok() => {
+            return (
+                CliCmd::Filter(FilterType::Qualifier, FilterOp::EQ),
+                Some((*x).to_string()),
+            );
+        }
         ["/filter", "regex", x] => {
             return (
                 CliCmd::Filter(FilterType::Regex, FilterOp::EQ),
@@ -397,17 +1226,28 @@ fn get_user_input(rl: &mut rustyline::Editor<CmdCompleter>, tag: &str) -> (CliCm
             )
         }
         ["/filter", "score", x, y] => {
-            if let Ok(op) = FilterOp::from_str(*x) {
-                if y.parse::<f64>().is_ok() {
+            if let Ok(op) = FilterOp::from_str(x) {
+                if y.parse::<Score>().is_ok_and(Score::is_finite) {
                     return (
                         CliCmd::Filter(FilterType::Score, op),
                         Some((*y).to_string()),
                     );
                 }
+                return (CliCmd::Undefined, Some(String::from("invalid score")));
             }
         }
+        ["/goto", "bookmark", x] if x.parse::<usize>().is_ok() => {
+            return (CliCmd::GoBookmark, Some((*x).to_string()));
+        }
         ["/h" | "/help" | "/?"] => return (CliCmd::Help, None),
+        ["/jump", x] if *x == "last" || *x == "random" || x.parse::<usize>().is_ok() => {
+            return (CliCmd::Jump, Some((*x).to_string()));
+        }
         ["/q" | "/quit"] => return (CliCmd::QuitProgram, None),
+        ["/merge", "signatures"] => return (CliCmd::Merge(false), None),
+        ["/merge", "signatures", "apply"] => return (CliCmd::Merge(true), None),
+        ["/next", "score"] => return (CliCmd::GoScore(false), None),
+        ["/prev", "score"] => return (CliCmd::GoScore(true), None),
         ["/save"] => return (CliCmd::Save(false), None),
         ["/save", "force"] => return (CliCmd::Save(true), None),
         ["/set", x] => match *x {
@@ -422,6 +1262,49 @@ fn get_user_input(rl: &mut rustyline::Editor<CmdCompleter>, tag: &str) -> (CliCm
             "unknown" => return (CliCmd::SetQualifier(false), Some(String::from("unknown"))),
             _ => {}
         },
+        ["/set", "scoredigits", x] => {
+            if let Ok(digits) = x.parse::<usize>() {
+                return (CliCmd::Set(ConfigType::ScoreDigits(digits)), None);
+            }
+        }
+        ["/set", "linewidth", x] => {
+            if let Ok(width) = x.parse::<usize>() {
+                return (CliCmd::Set(ConfigType::LineWidth(width.max(1))), None);
+            }
+        }
+        ["/set", "scoreeps", x] => {
+            return match x.parse::<Score>() {
+                Ok(eps) if eps.is_finite() && eps >= 0.0 => {
+                    (CliCmd::Set(ConfigType::ScoreEpsilon(eps)), None)
+                }
+                _ => (CliCmd::Undefined, Some(String::from("invalid tolerance"))),
+            };
+        }
+        ["/set", "delimiter", x] => {
+            let delimiter = match *x {
+                "tab" | "\\t" => Some('\t'),
+                "space" => Some(' '),
+                "comma" => Some(','),
+                _ if x.chars().count() == 1 => x.chars().next(),
+                _ => None,
+            };
+            if let Some(c) = delimiter {
+                return (CliCmd::SetDelimiter, Some(c.to_string()));
+            }
+        }
+        ["/set", "signature", x] => {
+            if let Ok(display) = x.parse::<SignatureDisplay>() {
+                return (CliCmd::Set(ConfigType::Signature(display)), None);
+            }
+        }
+        ["/set", "labelnorm", x] => {
+            if let Ok(norm) = x.parse::<LabelScoreNorm>() {
+                return (CliCmd::Set(ConfigType::LabelScoreNorm(norm)), None);
+            }
+        }
+        ["/set", "profile", x] => return (CliCmd::SetProfile, Some((*x).to_string())),
+        ["/set", "samples", "head"] => return (CliCmd::Set(ConfigType::SamplesTail(false)), None),
+        ["/set", "samples", "tail"] => return (CliCmd::Set(ConfigType::SamplesTail(true)), None),
         ["/set", x, y] => {
             let mut all: bool = false;
             let mut op: bool = false;
@@ -441,10 +1324,20 @@ fn get_user_input(rl: &mut rustyline::Editor<CmdCompleter>, tag: &str) -> (CliCm
             match *x {
                 "benign" => return (CliCmd::SetQualifier(all), Some(String::from("benign"))),
                 "mixed" => return (CliCmd::SetQualifier(all), Some(String::from("mixed"))),
+                "dedup" => return (CliCmd::Set(ConfigType::Dedup(op)), None),
+                "fastnav" => return (CliCmd::Set(ConfigType::FastNav(op)), None),
+                "highlight" => return (CliCmd::Set(ConfigType::Highlight(op)), None),
+                "labeldesc" => return (CliCmd::Set(ConfigType::LabelDescription(op)), None),
+                "prettysamples" => return (CliCmd::Set(ConfigType::PrettySamples(op)), None),
+                "regexcase" => return (CliCmd::Set(ConfigType::RegexCase(op)), None),
                 "reverse" => return (CliCmd::Set(ConfigType::Reverse(op)), None),
+                "samplenum" => return (CliCmd::Set(ConfigType::SampleNumber(op)), None),
                 "samples" => return (CliCmd::Set(ConfigType::Samples(op)), None),
-                "samplescount" => return (CliCmd::Set(ConfigType::SamplesCount(count)), None),
-                "signature" => return (CliCmd::Set(ConfigType::Signature(op)), None),
+                // on or off is not a count
+                "samplescount" if !matches!(*y, "on" | "off") => {
+                    let count = if all { SAMPLES_COUNT_ALL } else { count };
+                    return (CliCmd::Set(ConfigType::SamplesCount(count)), None);
+                }
                 "suspicious" => {
                     return (CliCmd::SetQualifier(all), Some(String::from("suspicious")))
                 }
@@ -453,43 +1346,385 @@ fn get_user_input(rl: &mut rustyline::Editor<CmdCompleter>, tag: &str) -> (CliCm
                 _ => {}
             }
         }
+        ["/stats", "labels"] => return (CliCmd::Stats, Some(String::from("labels"))),
+        ["/stats", "signatures"] => return (CliCmd::Stats, Some(String::from("signatures"))),
+        ["/stats", "sizes"] => return (CliCmd::Stats, Some(String::from("sizes"))),
         ["/status"] => return (CliCmd::Status, None),
+        ["/status", "tidb"] => return (CliCmd::Status, Some(String::from("tidb"))),
+        ["/tag", "add", x] => return (CliCmd::Tag(true), Some((*x).to_string())),
+        ["/tag", "remove", x] => return (CliCmd::Tag(false), Some((*x).to_string())),
+        ["/tidb", "list"] => return (CliCmd::Tidb, None),
+        ["/token", "info", x] => return (CliCmd::Token, Some((*x).to_string())),
+        ["/explain", x] => return (CliCmd::Explain, Some((*x).to_string())),
+        ["/related"] => return (CliCmd::Related, None),
+        ["/resume"] => return (CliCmd::Resume, None),
         ["/x"] => return (CliCmd::Exit, None),
+        ["/x", "redo"] => return (CliCmd::Redo, None),
         _ => {}
     }
 
     (CliCmd::Undefined, None)
 }
 
+/// Parse the `;` separated arguments of `/filter and`, each as the arguments of `/filter`
+/// except `and`. `None` if any of them is invalid.
+fn parse_filters(s: &str) -> Option<Vec<Filter>> {
+    s.split(';')
+        .map(
+            |args| match parse_command(&format!("/filter {}", args.trim())) {
+                (CliCmd::Filter(t, op), opt) if t != FilterType::And => Some((t, op, opt)),
+                _ => None,
+            },
+        )
+        .collect()
+}
+
+/// Split the command line by whitespaces.
+/// The pattern argument of `/event regex`, `/filter regex`, `/filter label` and
+/// `/filter label-all` is the rest of the line including whitespaces.
+/// For `/filter labelcov`, the words from the 5th are joined as the last argument.
+/// For the other commands, the words from the 4th are joined as the last argument.
+fn split_args(line: &str) -> Vec<&str> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let pattern_idx = match words[..] {
+        ["/filter", "label", "=" | "<>", ..] => 3,
+        ["/filter", "labelcov", ..] => 4,
+        ["/event" | "/filter", "regex", ..] | ["/filter", "label" | "label-all", ..] => 2,
+        _ => 3,
+    };
+    if words.len() <= pattern_idx + 1 {
+        return words;
+    }
+
+    // find the start of the pattern in the line to keep whitespaces in the pattern
+    let mut rest = line.trim_start();
+    for _ in 0..pattern_idx {
+        rest = rest
+            .trim_start()
+            .split_once(char::is_whitespace)
+            .map_or("", |(_, r)| r);
+    }
+    let mut args = words[..pattern_idx].to_vec();
+    args.push(rest.trim());
+    args
+}
+
 fn show_help() {
     println!(
         "
 <enter key>                                              go to next page.
 <TAB Key>                                                commands auto completion.
 /b or b                                                  go back to previous page.
-/x                                                       exit from label mode.
+/x                                                       exit from the current filter layer. qualifiers and event filters are kept.
+/x redo                                                  go back into the filter layer exited last.
 #<cluster-id>                                            get into the label mode and show defail information of the label.
 
+/bookmark                                                bookmark the current cluster.
+/bookmarks                                               show bookmarked clusters.
+/compare <cluster-id> <cluster-id>                       compare tokens and labels of two clusters.
+/dump <path>                                             write current cluster as shown to the file without colors.
 /event clear                                             clear event filters.
 /event regex [!]<pattern>                                filter events in current cluster by regular expression.
+                                                         event filters stay on the cluster until /event clear.
+/explain <label-id>                                      show samples and signatures of the label with the events of current cluster.
+/export events <path>                                    write the events of current cluster to the file.
+/export labels <path>                                    write the labels of clusters in current layer to the file in csv.
+/export ndjson [<path>]                                  write clusters in current layer as a json object per line. stdout if no path.
+/export script <path>                                    write the filters of the layers as commands for --script.
 /filter label                                            filter qualified clusters by all labels.
 /filter label <label-id>                                 filter qualified clusters by the specified label.
+/filter label <> [<label-id>]                            filter clusters which do not have the label or any labels.
+/filter label-all <label-id> <label-id> ...              filter clusters which have all of the specified labels.
+/filter labelcount >|>=|=|<=|< <n>                       filter clusters by the number of distinct labels.
+/filter labelcov <label-id> >|>=|=|<=|< <fraction>       filter clusters by the fraction of events labeled by the label.
+                                                         <label-id> is <tidb-id>:<rule-id>. e.g. 5:12, 5 (any rule), :12 (any tidb)
 /filter count|score >|>=|=|<=|< <value>                  filter clusters by the number of event in cluster or it's score.
 /filter qualifier benign|mixed|suspicious|unknown        filter clusters by the manual qualifier of cluster.
 /filter regex [!]<pattern>                               filter the events of clusters by regular expression.
+/filter tag <name>                                       filter clusters which have the tag.
+/filter and <filter> ; <filter> ...                      filter clusters matched by all filters in one layer.
+                                                         e.g. /filter and qualifier unknown ; score >= 0.5
+/goto bookmark <n>                                       go to the n-th bookmarked cluster.
+/jump <index>|last|random                                go to the n-th, the last or a random cluster in this layer.
+/merge signatures                                        show the clusters of the same signature in this layer to merge.
+/merge signatures apply                                  merge the listed clusters after confirmation.
+                                                         clusters of changed or different qualifiers are skipped.
+/next score                                              go to the cluster with the nearest lower score.
+/prev score                                              go to the cluster with the nearest higher score.
+/preview <filter arguments>                              show the number of clusters /filter would match.
+/related                                                 show clusters which have the top representative label of current cluster.
+/resume                                                  filter unknown clusters and go to the first one. /x to go back.
 /quit or /q                                              quit this program.
-/save [force]                                            save or overwrite if force option set.
+/save [force]                                            save changed qualifiers, or overwrite the file if force option set.
 /set csvstyle on|off                                     set message display style.
-/set reverse on|off                                      navigate reverse direction.
+/set dedup on|off                                        show identical events once with (xN). (default: off)
+/set delimiter <char>|tab|space|comma                    reload events with the delimiter.
+/set fastnav on|off                                      show only summary of clusters while paging fast.
+/set highlight on|off                                    highlight the words of samples in signatures of tidb token rules.
+/set labeldesc on|off                                    show description of labels.
+/set labelnorm size|labeled|raw                          divide cluster label scores by cluster size, the number of
+                                                         labeled events or nothing. (default: size)
+/set linewidth <n>                                       truncate samples longer than n bytes. (default: 500)
+/set prettysamples on|off                                show samples as alias=value pairs of columns.
+/set profile <name>                                      re-tokenize events with the feature columns of weight profile.
+/set regexcase on|off                                    match regular expressions case sensitively. (default: on)
+/set reverse on|off                                      navigate reverse direction and reverse the order of /stats.
+/set samplenum on|off                                    show the index of samples in the (filtered) events of cluster.
 /set samples on|off                                      show samples.
-/set samplescount <count>                                change sample display count.
-/set signature on|off                                    show signature of cluster.
+/set samples head|tail                                   show samples from the first or the last event.
+/set samplescount <count>                                change sample display count. (minimum: 1)
+/set samplescount all                                    show all events of clusters
+/set scoredigits <n>                                     set decimal digits of cluster scores. (default: 3)
+/set scoreeps <tolerance>                                set tolerance of score and labelcov comparison for = and <>. (default: 0.001)
+/set signature on|off|truncated|full                     show signature of cluster. on is truncated to 200 chars.
 /set tokens on|off                                       show tokens and it's matching result in the cluster.
 /set benign|mixed|suspicious|unknown [all]               set qualifier cluster or all clusters of current layer.
+/stats labels                                            show the number of clusters by label.
+/stats signatures                                        show the number of clusters by signature prefix.
+/stats sizes                                             show the histogram of cluster sizes in powers of ten.
 /status                                                  show status.
+/status tidb                                             show versions of loaded tidb.
+/tag add|remove <name>                                   add or remove the tag of current cluster.
+/tidb list                                               show id, name, version, kind and the number of patterns of tidbs.
+/token info <word>                                       show the columns where the token is extracted and an example event.
 /help or /? or ?                                         show help message.\n"
     );
     // TODO
     // set label <label-id>                               set label to cluster.
     // remove label <label-id> ...                        remove the specified labels.
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_exported_script_with_confirmed_regex_layer() {
+        let dir = std::env::temp_dir().join(format!("labeler-{}-replay", std::process::id()));
+        std::fs::create_dir_all(dir.join("tidb")).expect("writable temp dir");
+        let files = [
+            (
+                "events.csv",
+                "e1,GET /a.php\ne2,GET /b.php\ne3,POST /login.php\ne4,GET /favicon.ico\n",
+            ),
+            (
+                "clusters.json",
+                r#"{"detector_id":1,"events_count":4,"clusters_count":3,"outlier_count":0,
+                "clusters":[
+                {"cluster_id":1,"cluster_size":2,"signature":null,"score":0.9,"events":["e1","e2"]},
+                {"cluster_id":2,"cluster_size":1,"signature":null,"score":0.5,"events":["e3"]},
+                {"cluster_id":3,"cluster_size":1,"signature":null,"score":0.1,"events":["e4"]}],
+                "outliers":[]}"#,
+            ),
+            (
+                "labels.json",
+                r#"{"representative_labels":0,"event_labels":0,"representative":[],"events":[]}"#,
+            ),
+        ];
+        for (file, content) in &files {
+            std::fs::write(dir.join(file), content).expect("writable temp dir");
+        }
+        let cfg = Config::from_dir(&dir.display().to_string()).expect("valid dataset");
+        let load = || TitleMatch::new(&cfg, false, false).expect("valid dataset");
+        let (script, replayed) = (dir.join("script.txt"), dir.join("replayed.txt"));
+        let (script, replayed) = (script.display().to_string(), replayed.display().to_string());
+
+        let mut champion = load();
+        let mut session = Session::default();
+        let regex = CliCmd::Filter(FilterType::Regex, FilterOp::EQ);
+        let mut filter = |cmdtype, opt: &str| {
+            dispatch(
+                &cfg,
+                &mut champion,
+                &mut session,
+                cmdtype,
+                Some(opt.to_string()),
+            )
+            .ok()
+        };
+        assert_eq!(filter(regex, "php"), Some(Flow::Skip));
+        assert_eq!(filter(regex, "/"), Some(Flow::Confirm));
+        push_confirmed(&mut champion, &mut session, regex, Some(String::from("/")));
+        let count = CliCmd::Filter(FilterType::Count, FilterOp::EQ);
+        let _r = dispatch(
+            &cfg,
+            &mut champion,
+            &mut session,
+            count,
+            Some(String::from("2")),
+        );
+        assert_eq!(champion.export_script(&script).ok(), Some(3));
+
+        let mut champion = load();
+        let replay = run_script(&cfg, &mut champion, Session::default(), &script, true);
+        let exported = champion.export_script(&replayed);
+        let (expected, actual) = (
+            std::fs::read_to_string(&script),
+            std::fs::read_to_string(&replayed),
+        );
+        std::fs::remove_dir_all(&dir).expect("removable temp dir");
+        assert!(replay.is_ok());
+        assert_eq!(exported.ok(), Some(3));
+        assert_eq!(actual.ok(), expected.ok());
+    }
+
+    #[test]
+    fn merge_signatures_after_listing() {
+        let dir = std::env::temp_dir().join(format!("labeler-{}-merge", std::process::id()));
+        std::fs::create_dir_all(dir.join("tidb")).expect("writable temp dir");
+        let files = [
+            ("events.csv", "e1,GET /a\ne2,GET /a\ne3,GET /b\n"),
+            (
+                "clusters.json",
+                r#"{"detector_id":1,"events_count":3,"clusters_count":3,"outlier_count":0,
+                "clusters":[
+                {"cluster_id":1,"cluster_size":1,"signature":"GET /a","score":0.9,"events":["e1"]},
+                {"cluster_id":2,"cluster_size":1,"signature":"GET /a","score":0.5,"events":["e2"]},
+                {"cluster_id":3,"cluster_size":1,"signature":"GET /b","score":0.1,"events":["e3"]}],
+                "outliers":[]}"#,
+            ),
+            (
+                "labels.json",
+                r#"{"representative_labels":0,"event_labels":0,"representative":[],"events":[]}"#,
+            ),
+        ];
+        for (file, content) in &files {
+            std::fs::write(dir.join(file), content).expect("writable temp dir");
+        }
+        let cfg = Config::from_dir(&dir.display().to_string()).expect("valid dataset");
+        let champion = TitleMatch::new(&cfg, false, false);
+        std::fs::remove_dir_all(&dir).expect("removable temp dir");
+        let mut champion = champion.expect("valid dataset");
+
+        let mut session = Session {
+            bookmarks: vec![2, 3],
+            ..Session::default()
+        };
+        let mut merge = |apply| {
+            dispatch(
+                &cfg,
+                &mut champion,
+                &mut session,
+                CliCmd::Merge(apply),
+                None,
+            )
+            .ok()
+        };
+        assert_eq!(merge(true), Some(Flow::Skip));
+        assert_eq!(merge(false), Some(Flow::Skip));
+        assert_eq!(merge(true), Some(Flow::ConfirmMerge));
+        merge_confirmed(&mut champion, &mut session);
+        assert_eq!(champion.layer_len(), 2);
+        assert_eq!(session.bookmarks, vec![3]);
+        assert_eq!(session.merge_listing, None);
+    }
+
+    #[test]
+    fn split_args_by_token_count() {
+        assert_eq!(split_args("/set samples on"), vec!["/set", "samples", "on"]);
+        assert_eq!(
+            split_args("/filter count > 10"),
+            vec!["/filter", "count", ">", "10"]
+        );
+        assert_eq!(
+            split_args("/export events a  b c"),
+            vec!["/export", "events", "a", "b c"]
+        );
+    }
+
+    #[test]
+    fn split_args_keeps_whitespaces_of_patterns() {
+        assert_eq!(
+            split_args("/filter regex GET  /a b"),
+            vec!["/filter", "regex", "GET  /a b"]
+        );
+        assert_eq!(
+            split_args("/filter label = 1:2 3:4"),
+            vec!["/filter", "label", "=", "1:2 3:4"]
+        );
+        assert_eq!(
+            split_args("/filter labelcov 1:2 >= 0.5 x"),
+            vec!["/filter", "labelcov", "1:2", ">=", "0.5 x"]
+        );
+    }
+
+    #[test]
+    fn parse_filter_count() {
+        assert_eq!(
+            parse_command("/filter count > 10"),
+            (
+                CliCmd::Filter(FilterType::Count, FilterOp::G),
+                Some(String::from("10"))
+            )
+        );
+        let invalid = (CliCmd::Undefined, Some(String::from("invalid count")));
+        assert_eq!(
+            parse_command("/filter count > 99999999999999999999"),
+            invalid
+        );
+        assert_eq!(parse_command("/filter count > -1"), invalid);
+        assert_eq!(parse_command("/preview count <= -1"), invalid);
+    }
+
+    #[test]
+    fn parse_filter_score() {
+        assert_eq!(
+            parse_command("/filter score >= -0.5"),
+            (
+                CliCmd::Filter(FilterType::Score, FilterOp::GE),
+                Some(String::from("-0.5"))
+            )
+        );
+        let invalid = (CliCmd::Undefined, Some(String::from("invalid score")));
+        assert_eq!(parse_command("/filter score > inf"), invalid);
+        assert_eq!(parse_command("/filter score < NaN"), invalid);
+        assert_eq!(parse_command("/filter score > 1e300"), invalid);
+    }
+
+    #[test]
+    fn parse_filter_labelcov() {
+        assert_eq!(
+            parse_command("/filter labelcov 1:2 >= 0.5"),
+            (
+                CliCmd::Filter(FilterType::LabelCoverage, FilterOp::GE),
+                Some(String::from("1:2 0.5"))
+            )
+        );
+        let invalid = (CliCmd::Undefined, Some(String::from("invalid fraction")));
+        assert_eq!(parse_command("/filter labelcov 1:2 = NaN"), invalid);
+        assert_eq!(parse_command("/filter labelcov 1:2 < inf"), invalid);
+        assert_eq!(parse_command("/filter labelcov 1:2 > 1.5"), invalid);
+        assert_eq!(parse_command("/filter labelcov 1:2 > -0.1"), invalid);
+    }
+
+    #[test]
+    fn parse_set_scoreeps() {
+        assert_eq!(
+            parse_command("/set scoreeps 0.01"),
+            (CliCmd::Set(ConfigType::ScoreEpsilon(0.01)), None)
+        );
+        let invalid = (CliCmd::Undefined, Some(String::from("invalid tolerance")));
+        assert_eq!(parse_command("/set scoreeps inf"), invalid);
+        assert_eq!(parse_command("/set scoreeps NaN"), invalid);
+        assert_eq!(parse_command("/set scoreeps -0.1"), invalid);
+    }
+
+    #[test]
+    fn parse_set_samplescount() {
+        assert_eq!(
+            parse_command("/set samplescount 0"),
+            (CliCmd::Set(ConfigType::SamplesCount(0)), None)
+        );
+        assert_eq!(
+            parse_command("/set samplescount all"),
+            (
+                CliCmd::Set(ConfigType::SamplesCount(SAMPLES_COUNT_ALL)),
+                None
+            )
+        );
+        assert_eq!(
+            parse_command("/set samplescount on"),
+            (CliCmd::Undefined, None)
+        );
+    }
+}
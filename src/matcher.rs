@@ -1,24 +1,89 @@
 use crate::cluster::Clusters;
-use crate::config::Config;
+use crate::config::{Config, DEFAULT_PROFILE};
 use crate::events::Events;
 use crate::labels::Labels;
+use crate::parser;
 use crate::tidb::ComplexRules;
-use crate::{bold, CliConf, ClusterId, EventType, FilterOp, FilterType, Qualifier, RuleId, TidbId};
-use anyhow::{anyhow, Result};
-use log::info;
+use crate::{
+    bold, CliConf, ClusterId, EventType, Filter, FilterOp, FilterType, LabelScoreNorm, MessageId,
+    PatternId, Qualifier, RuleId, Score, TidbId,
+};
+use ansi_term::Style;
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use num_format::{Locale, ToFormattedString};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+const LABEL_DESCRIPTION_DISPLAY_LENGTH: usize = 80;
+const MAX_UNLOADED_CLUSTERS_DISPLAY: usize = 10;
+const MAX_OUTLIER_OVERLAP_DISPLAY: usize = 10;
+const EXPLAIN_EVENTS_DISPLAY_COUNT: usize = 5;
+const NOTABLE_SCORE: Score = 0.5;
+const SIGNATURE_MERGE_DISPLAY_LENGTH: usize = 60;
+const MAX_FILTER_CACHE: usize = 64;
+
+type MergeConflicts = Vec<(Vec<ClusterId>, &'static str)>;
 
 /// This structure stores the result of `cli` command `/filter ipaddr/regex/label/...`
 #[derive(Default)]
 pub struct FilteredClusters {
+    id: usize, // unique in a session. layers are not changed after pushed
     filtertype: FilterType,
     op: FilterOp,
     pattern: String,
     clusters: Vec<ClusterId>,
 }
 
+/// The result of pushing a filter layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushResult {
+    Pushed(usize), // the number of clusters in the new layer
+    Same,          // the same clusters as the current layer. not pushed
+    NeedsConfirm,  // a regex matching all clusters of the current layer. see `push_pending`
+    Empty,         // no cluster matched
+    Full,          // too many filter layers
+}
+
+impl FilteredClusters {
+    /// Return the `/filter` command which makes this layer. `None` for the base layer.
+    fn command(&self) -> Option<String> {
+        let args = match (self.filtertype, self.op) {
+            (FilterType::NoFilter, _) => return None,
+            (FilterType::Label, FilterOp::NE) if self.pattern == "All" => String::from("label <>"),
+            (FilterType::Label, FilterOp::NE) => format!("label <> {}", self.pattern),
+            (FilterType::Label, _) if self.pattern == "All" => String::from("label"),
+            (FilterType::Regex, FilterOp::NE) => format!("regex !{}", self.pattern),
+            (ft, _) => format!("{} {}", filter_name(ft), self.pattern),
+        };
+        Some(format!("/filter {}", args))
+    }
+}
+
+/// The name of the filter type in `/filter` commands.
+fn filter_name(ft: FilterType) -> &'static str {
+    match ft {
+        FilterType::And => "and",
+        FilterType::Count => "count",
+        FilterType::Label => "label",
+        FilterType::LabelAll => "label-all",
+        FilterType::LabelCoverage => "labelcov",
+        FilterType::LabelCount => "labelcount",
+        FilterType::Qualifier => "qualifier",
+        FilterType::Regex => "regex",
+        FilterType::Score => "score",
+        FilterType::Tag => "tag",
+        _ => "",
+    }
+}
+
 impl fmt::Display for FilteredClusters {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if self.filtertype == FilterType::NoFilter {
@@ -29,47 +94,135 @@ impl fmt::Display for FilteredClusters {
     }
 }
 
+/// Summary of the loaded dataset
+#[derive(Serialize)]
+pub struct Statistics {
+    clusters: usize,
+    labeled_clusters: usize,
+    labeled_events: usize,
+    representatives: usize,
+    events_loaded: usize,
+    min_score: Option<Score>,
+    max_score: Option<Score>,
+    notable_clusters: usize, // clusters of score `NOTABLE_SCORE` or higher
+}
+
+/// A line of `/export ndjson`
+#[derive(Serialize)]
+struct ClusterExport<'a> {
+    cluster_id: ClusterId,
+    qualifier: Qualifier,
+    size: usize,
+    score: Option<Score>,
+    signature: Option<&'a str>,
+    tags: Vec<String>,
+    labels: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct SavedQualifier {
+    cluster_id: ClusterId,
+    qualifier: Qualifier,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+}
+
 pub struct TitleMatch {
     clusters: Clusters,
     events: Events,
     tidbs: Vec<ComplexRules>,
+    tidb_versions: Vec<String>,
+    signature_tokens: HashSet<String>, // tokens of signatures of token rules in tidb
     labels: Labels,
     rounds: Vec<FilteredClusters>,
+    popped: Vec<FilteredClusters>, // layers removed by `remove_filter` to restore. the last is the latest
+    pending: Option<FilteredClusters>, // layer not pushed by `push_layer` for the same clusters
+    next_layer_id: usize,
+    filter_cache: HashMap<(usize, String), Vec<ClusterId>>, // (layer id, filter) -> clusters
+    max_layers: usize,
+    profile: String,
 }
 
 impl TitleMatch {
+    /// Empty clusters or events are warned instead of error if `allow_empty` is set.
+    /// The durations of loading stages are printed to stderr if `timings` is set.
+    ///
     /// # Errors
     ///
     /// Will return `Err` if it fails to connect postgres db or datasource not found
-    pub fn new(cfg: &Config) -> Result<Self> {
+    pub fn new(cfg: &Config, allow_empty: bool, timings: bool) -> Result<Self> {
         if EventType::Packet == cfg.event_type() {
             return Err(anyhow!("unsupported log type {:?}", cfg.event_type()));
         }
+        let mut stages: Vec<(&str, Duration)> = Vec::new();
+        let mut start = Instant::now();
+        let mut lap = |stage| {
+            stages.push((stage, start.elapsed()));
+            start = Instant::now();
+        };
 
         info!("loading labels");
         let labels = Labels::new(cfg.labels())?;
+        lap("labels");
 
         info!("loading clusters");
-        let mut clusters = Clusters::new(cfg.clusters(), &labels, cfg.delimiter())?;
+        let key_index = cfg
+            .key_field()
+            .ok_or_else(|| anyhow!("key_field does not set"))?;
+        let mut clusters = Clusters::new(cfg.clusters(), &labels, cfg.delimiter(), key_index)?;
         if clusters.is_empty() {
-            return Err(anyhow!("clusters not found."));
+            if !allow_empty {
+                return Err(anyhow!("clusters not found."));
+            }
+            warn!("clusters not found.");
+            eprintln!("Warning: clusters not found.");
         }
         info!("{} clusters are loaded.", clusters.len());
+        warn_outlier_overlap(&clusters);
+        lap("clusters");
 
         info!("loading events");
         let events = Events::new(cfg, clusters.event_ids())?;
         if events.is_empty() {
-            return Err(anyhow!("events not found."));
+            if !allow_empty {
+                return Err(anyhow!("events not found."));
+            }
+            warn!("events not found.");
+            eprintln!("Warning: events not found.");
         }
         info!("{} events are loaded.", events.len());
+        lap("events");
 
         clusters.init_event_tokens(&events);
+        if let Some(column) = cfg.signature_column() {
+            let count = clusters.init_signatures(&events, column);
+            info!(
+                "signatures of {} clusters are set from column {}",
+                count, column
+            );
+        }
+        warn_if_incomplete(&clusters, &events);
+        lap("tokens");
 
         info!("loading tidb");
-        let tidbs = ComplexRules::new(cfg.tidb())?;
+        let tidbs = ComplexRules::new(cfg.tidb(), cfg.tidb_versions())?;
+        lap("tidb");
+        if timings {
+            for (stage, elapsed) in &stages {
+                eprintln!("{:<10} {:>10.3}s", stage, elapsed.as_secs_f64());
+            }
+            let total: Duration = stages.iter().map(|(_, elapsed)| *elapsed).sum();
+            eprintln!("{:<10} {:>10.3}s", "total", total.as_secs_f64());
+        }
+
+        let signature_tokens = tidbs
+            .iter()
+            .flat_map(|tidb| tidb.signature_tokens(events.token_chars()))
+            .collect();
 
         // init base(bottom filter) layer
         let rounds: Vec<FilteredClusters> = vec![FilteredClusters {
+            id: 0,
             filtertype: FilterType::default(),
             op: FilterOp::default(),
             pattern: String::from("Clusters"),
@@ -80,20 +233,156 @@ impl TitleMatch {
             clusters,
             events,
             tidbs,
+            tidb_versions: cfg.tidb_versions().to_vec(),
+            signature_tokens,
             labels,
             rounds,
+            popped: Vec::new(),
+            pending: None,
+            next_layer_id: 1,
+            filter_cache: HashMap::new(),
+            max_layers: cfg.max_filter_layers(),
+            profile: DEFAULT_PROFILE.to_string(),
         })
     }
 
-    pub fn show_statistics(&self) {
-        let (labeled_clusters, labeled_events, representative_labels) = self.labels.statistics();
-        println!(
-            "{:>6} clusters\n{:>6} labeled clusters\n{:>6} labeled events\n{:>6} representatives",
-            self.clusters.len(),
+    #[must_use]
+    pub fn statistics(&self) -> Statistics {
+        let (labeled_clusters, labeled_events, representatives) = self.labels.statistics();
+        let score_range = self.clusters.score_range();
+        Statistics {
+            clusters: self.clusters.len(),
             labeled_clusters,
             labeled_events,
-            representative_labels
+            representatives,
+            events_loaded: self.events.len(),
+            min_score: score_range.map(|(min, _)| min),
+            max_score: score_range.map(|(_, max)| max),
+            notable_clusters: self.clusters.count_score_above(NOTABLE_SCORE),
+        }
+    }
+
+    pub fn show_statistics(&self) {
+        let _r = self.write_statistics(&mut std::io::stdout().lock());
+    }
+
+    /// Write the statistics as `show_statistics` shows.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if it fails to write to `out`.
+    pub fn write_statistics(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        let stats = self.statistics();
+        writeln!(
+            out,
+            "{:>6} clusters\n{:>6} labeled clusters\n{:>6} labeled events\n{:>6} representatives",
+            stats.clusters, stats.labeled_clusters, stats.labeled_events, stats.representatives
+        )?;
+        if let (Some(min), Some(max)) = (stats.min_score, stats.max_score) {
+            writeln!(
+                out,
+                "{:>6} clusters of score >= {} (scores {:.3} - {:.3})",
+                stats.notable_clusters, NOTABLE_SCORE, min, max
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Return (the number of events referenced by clusters, the number of loaded events)
+    #[must_use]
+    pub fn coverage(&self) -> (usize, usize) {
+        (self.clusters.referenced_events_count(), self.events.len())
+    }
+
+    pub fn show_status(&self) {
+        let (referenced, loaded) = self.coverage();
+        #[allow(clippy::cast_precision_loss)]
+        let ratio = if referenced > 0 {
+            loaded as f64 * 100.0 / referenced as f64
+        } else {
+            0.0
+        };
+        println!(
+            "loaded {} of {} referenced events ({:.0}%)",
+            loaded.to_formatted_string(&Locale::en),
+            referenced.to_formatted_string(&Locale::en),
+            ratio
+        );
+        println!("profile: {}", self.profile);
+        println!(
+            "tokens: {}",
+            self.clusters.token_count().to_formatted_string(&Locale::en)
         );
+        println!("filter layers: {}", self.rounds.len());
+    }
+
+    pub fn show_tidb_status(&self) {
+        if self.tidbs.is_empty() {
+            println!("no tidb loaded.");
+        }
+        for tidb in &self.tidbs {
+            println!(
+                "{:>6} {} version {}{}",
+                tidb.id(),
+                tidb.name(),
+                tidb.version(),
+                if tidb.is_compatible(&self.tidb_versions) {
+                    String::new()
+                } else {
+                    format!(" (expected {:?})", self.tidb_versions)
+                }
+            );
+        }
+    }
+
+    /// Print the loaded tidbs in order of id. Tidbs of the same id are marked as duplicate.
+    pub fn show_tidb_list(&self) {
+        if self.tidbs.is_empty() {
+            println!("no tidb loaded.");
+        }
+        for (idx, tidb) in self.tidbs.iter().enumerate() {
+            let duplicate = idx > 0 && self.tidbs[idx - 1].id() == tidb.id();
+            println!(
+                "{:>6} {} version {}, {:?}, {} patterns{}",
+                tidb.id(),
+                tidb.name(),
+                tidb.version(),
+                tidb.kind(),
+                tidb.patterns_count(),
+                if duplicate { " (duplicate)" } else { "" }
+            );
+        }
+    }
+
+    /// Print the columns where `word` is extracted as a token and an event having it.
+    pub fn show_token_info(&self, word: &str) {
+        let lowercase = word.to_lowercase();
+        let token = if self.events.token_sources(word).is_empty() {
+            lowercase.as_str()
+        } else {
+            word
+        };
+        let columns = self.events.token_sources(token);
+        if columns.is_empty() {
+            println!("token \"{}\" not found.", word);
+            return;
+        }
+        let aliases = self.events.aliases();
+        let sources: Vec<String> = columns
+            .iter()
+            .map(|&column| {
+                aliases.get(column).map_or_else(
+                    || format!("column {}", column),
+                    |alias| format!("{}({})", alias, column),
+                )
+            })
+            .collect();
+        println!("token \"{}\" from {}", token, sources.join(", "));
+        if let Some(message_id) = self.events.token_example(token) {
+            if let Some(message) = self.events.get_message(message_id) {
+                println!("example: {}", message);
+            }
+        }
     }
 
     #[must_use]
@@ -101,43 +390,202 @@ impl TitleMatch {
         self.clusters.len()
     }
 
+    /// Return the number of clusters in the current(top) filter layer.
+    #[must_use]
+    pub fn layer_len(&self) -> usize {
+        self.rounds.last().map_or(0, |r| r.clusters.len())
+    }
+
+    /// Re-tokenize the loaded events with the feature columns of the named weight profile.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the profile is not defined in config.
+    pub fn set_profile(&mut self, cfg: &Config, name: &str) -> Result<()> {
+        let features = cfg.profile_features(name).ok_or_else(|| {
+            anyhow!(
+                "profile \"{}\" not found. available profiles: {}",
+                name,
+                cfg.profile_names().join(", ")
+            )
+        })?;
+
+        let (old_tokens, old_distinct) = (self.events.tokens_count(), self.clusters.token_count());
+        let new_tokens = self.events.retokenize(
+            &cfg.feature_types(&features),
+            parser::normalizer(cfg.token_normalizer()).as_ref(),
+        );
+        self.clusters.init_event_tokens(&self.events);
+        warn_if_incomplete(&self.clusters, &self.events);
+        let new_distinct = self.clusters.token_count();
+        info!(
+            "profile \"{}\" -> \"{}\", feature columns {:?}, tokens {} -> {}, distinct tokens {} -> {}",
+            self.profile, name, features, old_tokens, new_tokens, old_distinct, new_distinct
+        );
+        println!(
+            "profile {}: {} tokens ({} distinct), was {} tokens ({} distinct)",
+            name, new_tokens, new_distinct, old_tokens, old_distinct
+        );
+        self.profile = name.to_string();
+        Ok(())
+    }
+
+    /// Reload events from the events file with `delimiter` and the feature columns of current profile.
+    /// The message ids of outliers, the signatures from the signature column and the tokens of
+    /// signatures are set again from the reloaded events.
+    /// The previous events are kept if no event is loaded.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if events are in json format, it fails to read events, or no event is loaded.
+    pub fn set_delimiter(&mut self, cfg: &Config, delimiter: char) -> Result<()> {
+        if cfg.json_columns().is_some() {
+            return Err(anyhow!("delimiter is not used for json events."));
+        }
+        let key_index = cfg
+            .key_field()
+            .ok_or_else(|| anyhow!("key_field does not set"))?;
+        let features = cfg
+            .profile_features(&self.profile)
+            .unwrap_or_else(|| cfg.features());
+        let events = Events::load(
+            cfg,
+            self.clusters.event_ids_by_delimiter(delimiter, key_index),
+            &cfg.feature_types(&features),
+            delimiter,
+        )?;
+        let (skipped, notfound) = events.load_counts();
+        println!(
+            "delimiter {:?}: {} events loaded, {} skipped, {} not found",
+            delimiter,
+            events.len(),
+            skipped,
+            notfound
+        );
+        if events.is_empty() {
+            return Err(anyhow!(
+                "no events loaded. delimiter {:?} is kept.",
+                self.events.delimiter()
+            ));
+        }
+        info!(
+            "delimiter {:?} -> {:?}, {} events loaded",
+            self.events.delimiter(),
+            delimiter,
+            events.len()
+        );
+        self.events = events;
+        self.clusters.set_delimiter(delimiter, key_index);
+        self.clusters.init_event_tokens(&self.events);
+        if let Some(column) = cfg.signature_column() {
+            self.clusters.init_signatures(&self.events, column);
+        }
+        self.signature_tokens = self
+            .tidbs
+            .iter()
+            .flat_map(|tidb| tidb.signature_tokens(self.events.token_chars()))
+            .collect();
+        warn_if_incomplete(&self.clusters, &self.events);
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn profile(&self) -> &str {
+        &self.profile
+    }
+
+    /// Print only the summary line of the cluster.
+    pub fn print_cluster_summary(&self, idx: usize) {
+        if let Some(cid) = self.rounds.last().and_then(|last| last.clusters.get(idx)) {
+            print!("[{}]", idx);
+            self.clusters.print_summary(*cid);
+        }
+    }
+
     pub fn print_cluster(&self, idx: usize, cfg: &CliConf) {
+        let _r = self.write_cluster(
+            &mut std::io::stdout().lock(),
+            idx,
+            cfg,
+            crate::is_color_enabled(),
+        );
+    }
+
+    /// Write the cluster at `idx` to `path` as `print_cluster` shows, without ANSI colors.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the cluster is not found or it fails to write the file.
+    pub fn dump_cluster(&self, idx: usize, path: &str, cfg: &CliConf) -> Result<()> {
+        if self.cluster_id(idx).is_none() {
+            return Err(anyhow!("no cluster selected."));
+        }
+        let mut out = BufWriter::new(File::create(path).with_context(|| path.to_string())?);
+        self.write_cluster(&mut out, idx, cfg, false)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Write the cluster at `idx` with its labels, in colors if `color` is set.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if it fails to write to `out`.
+    pub fn write_cluster(
+        &self,
+        out: &mut dyn Write,
+        idx: usize,
+        cfg: &CliConf,
+        color: bool,
+    ) -> std::io::Result<()> {
         if let Some(last) = self.rounds.last() {
             if idx >= last.clusters.len() {
-                return;
+                return Ok(());
             }
             let cid = last.clusters[idx];
-            print!("[{}]", idx);
-            self.clusters.print(cid, &self.events, cfg);
+            write!(out, "[{}]", idx)?;
+            self.clusters
+                .write(out, cid, &self.events, cfg, &self.signature_tokens, color)?;
 
             let cluster_size = u32::try_from(self.clusters.size(cid)).unwrap_or_default();
+            let dividend = self.label_score_divisor(cid, cfg.label_score_norm());
+            let bold = crate::style_if(color, Style::new().bold());
             if let Some(matched) = self.labels.get_representative_labels(cid) {
-                println!("\n{}", bold!("Cluster label(s):"));
+                writeln!(out, "\n{}", bold.paint("Cluster label(s):"))?;
                 for (tidb_id, rule_id, count, score) in matched {
                     if let Some(name) = Self::get_label_name(self, *tidb_id, *rule_id) {
-                        let score = f64::try_from(*score).unwrap_or_default();
-                        let dividend = f64::try_from(cluster_size).unwrap_or_default();
+                        let score = f64::from(*score);
                         if dividend > 0.0 {
-                            println!(
-                                "{:.03} {}/{} {}:{} {}",
+                            writeln!(
+                                out,
+                                "{:.03} {}/{} {}:{} {}{}",
                                 score / dividend,
                                 count,
                                 cluster_size,
                                 tidb_id,
                                 rule_id,
-                                name
-                            );
+                                name,
+                                self.label_description(*tidb_id, *rule_id, cfg)
+                            )?;
                         }
                     }
                 }
             }
 
             if let Some(matched) = self.labels.get_event_labels(cid) {
-                println!("\n{}", bold!("Event label(s):"));
+                writeln!(out, "\n{}", bold.paint("Event label(s):"))?;
                 let mut unknowns = Vec::new();
                 for ((tidb_id, rule_id), count) in matched {
                     if let Some(name) = Self::get_label_name(self, tidb_id, rule_id) {
-                        println!("{:>4} {}:{} {}", count, tidb_id, rule_id, name);
+                        writeln!(
+                            out,
+                            "{:>4} {}:{} {}{}",
+                            count,
+                            tidb_id,
+                            rule_id,
+                            name,
+                            self.label_description(tidb_id, rule_id, cfg)
+                        )?;
                     } else {
                         unknowns.push(tidb_id);
                     }
@@ -147,13 +595,292 @@ impl TitleMatch {
                 unknowns.dedup();
                 for tidb_id in unknowns {
                     if let Some(name) = Self::get_tidb_name(self, tidb_id) {
-                        println!("{} {}", tidb_id, name);
+                        writeln!(out, "{} {}", tidb_id, name)?;
                     } else {
-                        println!("{:>8}:", tidb_id);
+                        writeln!(out, "{:>8}:", tidb_id)?;
                     }
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Print the histogram of cluster sizes in current layer, from the largest sizes if
+    /// `reverse`.
+    pub fn show_size_histogram(&self, reverse: bool) {
+        const BAR_WIDTH: usize = 50;
+        if let Some(last) = self.rounds.last() {
+            let mut histogram = self.clusters.size_histogram(&last.clusters);
+            if reverse {
+                histogram.reverse();
+            }
+            let max = histogram.iter().map(|(_, n)| *n).max().unwrap_or(0);
+            println!("cluster sizes of {} clusters", last.clusters.len());
+            for (range, count) in histogram {
+                let bar = (count * BAR_WIDTH).checked_div(max).unwrap_or(0);
+                println!("{:>12} {:>8} {}", range, count, "#".repeat(bar));
+            }
+        }
+    }
+
+    /// Return the groups of clusters in current layer which have the same signature, and the
+    /// groups of them which cannot be merged with the reason. (see `Clusters::merge_conflict`)
+    /// The first cluster of each group is the one others are merged into.
+    #[must_use]
+    pub fn signature_merges(&self) -> (Vec<Vec<ClusterId>>, MergeConflicts) {
+        let groups = self.rounds.last().map_or_else(Vec::new, |last| {
+            self.clusters.same_signature_groups(&last.clusters)
+        });
+        let (mut merges, mut conflicts) = (Vec::new(), Vec::new());
+        for group in groups {
+            match self.clusters.merge_conflict(group[0], &group[1..]) {
+                Some(reason) => conflicts.push((group, reason)),
+                None => merges.push(group),
+            }
+        }
+        (merges, conflicts)
+    }
+
+    /// Print the groups of clusters to be merged by `merge_signatures`, and the groups which
+    /// are skipped.
+    pub fn show_signature_merges(&self) {
+        let (merges, conflicts) = self.signature_merges();
+        let signature = |cid: ClusterId| {
+            self.clusters
+                .full_signature(cid)
+                .unwrap_or_default()
+                .chars()
+                .take(SIGNATURE_MERGE_DISPLAY_LENGTH)
+                .collect::<String>()
+        };
+        for group in &merges {
+            let ids: Vec<String> = group.iter().map(ToString::to_string).collect();
+            let cleared = if self.clusters.has_filter(group[0]) {
+                " (event filters cleared)"
+            } else {
+                ""
+            };
+            println!(
+                "{} <- {}{}  {}",
+                ids[0],
+                ids[1..].join(", "),
+                cleared,
+                signature(group[0])
+            );
+        }
+        for (group, reason) in &conflicts {
+            let ids: Vec<String> = group.iter().map(ToString::to_string).collect();
+            println!(
+                "{} <- {}  skipped: {}  {}",
+                ids[0],
+                ids[1..].join(", "),
+                reason,
+                signature(group[0])
+            );
+        }
+        println!(
+            "{} clusters would be merged into {}",
+            merges.iter().map(Vec::len).sum::<usize>(),
+            merges.len()
+        );
+        if !conflicts.is_empty() {
+            println!(
+                "{} groups are skipped. clusters of changed or different qualifiers are not merged.",
+                conflicts.len()
+            );
+        }
+    }
+
+    /// Merge the clusters of the same signature in current layer into the first cluster of
+    /// each group. The merged clusters are removed from all layers. The groups which cannot
+    /// be merged are skipped. (see `signature_merges`)
+    ///
+    /// Return (the number of groups, the removed clusters, the number of clusters whose
+    /// event filters are cleared).
+    pub fn merge_signatures(&mut self) -> (usize, Vec<ClusterId>, usize) {
+        let (merges, _) = self.signature_merges();
+        let mut removed: Vec<ClusterId> = Vec::new();
+        let mut cleared = 0;
+        for group in &merges {
+            let (target, sources) = (group[0], &group[1..]);
+            let has_filter = self.clusters.has_filter(target);
+            if self.clusters.merge(target, sources) {
+                self.labels.merge(target, sources);
+                removed.extend(sources);
+                if has_filter {
+                    cleared += 1;
+                }
+            }
+        }
+        if !removed.is_empty() {
+            let removed: HashSet<ClusterId> = removed.iter().copied().collect();
+            for round in &mut self.rounds {
+                round.clusters.retain(|cid| !removed.contains(cid));
+            }
+            self.popped.clear();
+            self.filter_cache.clear();
+        }
+        (merges.len(), removed, cleared)
+    }
+
+    /// Print the signature groups of current layer, from the smallest group if `reverse`.
+    pub fn show_signature_groups(&self, reverse: bool) {
+        if let Some(last) = self.rounds.last() {
+            let mut groups = self.clusters.signature_groups(&last.clusters);
+            if reverse {
+                groups.reverse();
+            }
+            println!(
+                "{} signature groups in {} clusters",
+                groups.len(),
+                last.clusters.len()
+            );
+            for (signature, count) in groups {
+                println!("{:>6} {}", count, signature);
+            }
+        }
+    }
+
+    /// Return the number of clusters which have each label, in descending order of the count.
+    #[must_use]
+    pub fn label_frequency(&self, clusters: &[ClusterId]) -> Vec<(PatternId, usize)> {
+        let mut frequency: HashMap<PatternId, usize> = HashMap::new();
+        for cid in clusters {
+            for pattern in self.labels.labels_of(*cid) {
+                *frequency.entry(pattern).or_default() += 1;
+            }
+        }
+        let mut frequency: Vec<_> = frequency.into_iter().collect();
+        frequency.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        frequency
+    }
+
+    /// Print the label frequency of current layer, from the least frequent if `reverse`.
+    pub fn show_label_frequency(&self, reverse: bool) {
+        if let Some(last) = self.rounds.last() {
+            let mut frequency = self.label_frequency(&last.clusters);
+            if reverse {
+                frequency.reverse();
+            }
+            println!(
+                "{} labels in {} clusters",
+                frequency.len(),
+                last.clusters.len()
+            );
+            for ((tidb_id, rule_id), count) in frequency {
+                println!(
+                    "{:>6} {}:{} {}",
+                    count,
+                    tidb_id,
+                    rule_id,
+                    self.get_label_name(tidb_id, rule_id).unwrap_or_default()
+                );
+            }
+        }
+    }
+
+    /// Print the samples and signatures of the rule, and the events of the cluster labeled by it.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the cluster or the rule is not found.
+    pub fn explain_label(&self, cid: ClusterId, pattern: PatternId) -> Result<()> {
+        if !self.clusters.contains(cid) {
+            return Err(anyhow!("cluster #{} not found", cid));
+        }
+        let (tidb_id, rule_id) = pattern;
+        let rule = self
+            .tidbs
+            .iter()
+            .find_map(|tidb| tidb.get_rule(tidb_id, rule_id))
+            .ok_or_else(|| anyhow!("label {}:{} not found", tidb_id, rule_id))?;
+
+        println!(
+            "\n{}",
+            bold!(format!("{}:{} {}", tidb_id, rule_id, rule.name()))
+        );
+        println!("Samples:");
+        for s in rule.samples() {
+            println!("\t{}", s);
+        }
+        println!("Signatures:");
+        for s in rule.signatures() {
+            println!("\t{}", s);
+        }
+
+        let events = self.labels.events_of(cid, pattern);
+        println!(
+            "\n{}",
+            bold!(format!(
+                "{} events of cluster #{} labeled by {}:{}",
+                events.len(),
+                cid,
+                tidb_id,
+                rule_id
+            ))
+        );
+        for message_id in events.iter().take(EXPLAIN_EVENTS_DISPLAY_COUNT) {
+            if let Some(message) = self.events.get_message(message_id) {
+                println!("{}", message);
+            } else {
+                println!("{} (not loaded)", message_id);
+            }
+        }
+        if events.len() > EXPLAIN_EVENTS_DISPLAY_COUNT {
+            println!("...");
+        }
+        println!();
+        Ok(())
+    }
+
+    /// Print the tokens and labels of two clusters in three columns: only in `a`, shared, only in `b`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if either of the clusters is not found.
+    pub fn compare(&self, a: ClusterId, b: ClusterId) -> Result<()> {
+        for cid in &[a, b] {
+            if !self.clusters.contains(*cid) {
+                return Err(anyhow!("cluster #{} not found", cid));
+            }
+        }
+
+        let (tokens_a, tokens_b) = (self.clusters.tokens_of(a), self.clusters.tokens_of(b));
+        let to_sorted = |set: HashSet<&&str>| {
+            let mut v: Vec<String> = set.into_iter().map(ToString::to_string).collect();
+            v.sort_unstable();
+            v
+        };
+        print_columns(
+            "Tokens",
+            a,
+            b,
+            &[
+                to_sorted(tokens_a.difference(&tokens_b).collect()),
+                to_sorted(tokens_a.intersection(&tokens_b).collect()),
+                to_sorted(tokens_b.difference(&tokens_a).collect()),
+            ],
+        );
+
+        let (labels_a, labels_b) = (self.labels.labels_of(a), self.labels.labels_of(b));
+        let to_sorted = |set: HashSet<&PatternId>| {
+            let mut v: Vec<_> = set.into_iter().collect();
+            v.sort_unstable();
+            v.into_iter()
+                .map(|(tidb_id, rule_id)| format!("{}:{}", tidb_id, rule_id))
+                .collect::<Vec<_>>()
+        };
+        print_columns(
+            "Labels",
+            a,
+            b,
+            &[
+                to_sorted(labels_a.difference(&labels_b).collect()),
+                to_sorted(labels_a.intersection(&labels_b).collect()),
+                to_sorted(labels_b.difference(&labels_a).collect()),
+            ],
+        );
+        Ok(())
     }
 
     fn get_tidb_name(&self, tidb_id: TidbId) -> Option<&str> {
@@ -175,6 +902,256 @@ impl TitleMatch {
         None
     }
 
+    fn get_label_description(&self, tidb_id: TidbId, rule_id: RuleId) -> Option<&str> {
+        for tidb in &self.tidbs {
+            let r = tidb.get_label_description(tidb_id, rule_id);
+            if r.is_some() {
+                return r;
+            }
+        }
+        None
+    }
+
+    /// Return " - <description>" of the label if `/set labeldesc on`, otherwise empty string.
+    fn label_description(&self, tidb_id: TidbId, rule_id: RuleId, cfg: &CliConf) -> String {
+        if !cfg.is_label_description_on() {
+            return String::new();
+        }
+        self.get_label_description(tidb_id, rule_id)
+            .map(|desc| {
+                let desc = desc.split_whitespace().collect::<Vec<_>>().join(" ");
+                if desc.chars().count() > LABEL_DESCRIPTION_DISPLAY_LENGTH {
+                    format!(
+                        " - {}...",
+                        desc.chars()
+                            .take(LABEL_DESCRIPTION_DISPLAY_LENGTH)
+                            .collect::<String>()
+                    )
+                } else {
+                    format!(" - {}", desc)
+                }
+            })
+            .unwrap_or_default()
+    }
+
+    /// Return the id of the cluster at `idx` in the current layer.
+    #[must_use]
+    pub fn cluster_id(&self, idx: usize) -> Option<ClusterId> {
+        self.rounds.last()?.clusters.get(idx).copied()
+    }
+
+    /// Return the index of the cluster with the nearest higher (or lower) score than the cluster
+    /// at `from` in the current layer. Clusters of the same score are ordered by cluster id.
+    #[must_use]
+    pub fn neighbor_by_score(&self, from: usize, higher: bool) -> Option<usize> {
+        let last = self.rounds.last()?;
+        let mut ranked: Vec<(usize, Score, ClusterId)> = last
+            .clusters
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, cid)| self.clusters.score(*cid).map(|score| (idx, score, *cid)))
+            .collect();
+        // higher scores first
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.2.cmp(&b.2)));
+        let pos = ranked.iter().position(|(idx, _, _)| *idx == from)?;
+        let neighbor = if higher { pos.checked_sub(1)? } else { pos + 1 };
+        ranked.get(neighbor).map(|(idx, _, _)| *idx)
+    }
+
+    /// Return the event ids of the cluster at `idx` in the current layer, filtered by
+    /// the event filters of the cluster if any.
+    #[must_use]
+    pub fn filtered_event_ids(&self, idx: usize) -> Option<&[MessageId]> {
+        let cid = self.cluster_id(idx)?;
+        Some(self.clusters.active_event_ids(cid))
+    }
+
+    /// Write the events of the cluster at `idx` as shown in samples to `path`.
+    ///
+    /// Return the number of written events.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the cluster is not found or it fails to write the file.
+    pub fn export_events(&self, idx: usize, path: &str) -> Result<usize> {
+        let event_ids = self
+            .filtered_event_ids(idx)
+            .ok_or_else(|| anyhow!("Cluster {} not found", idx))?;
+        let file = File::create(path).with_context(|| format!("cannot create {}", path))?;
+        let mut writer = BufWriter::new(file);
+        for message_id in event_ids {
+            if let Some(msg) = self.events.get_message(message_id) {
+                writeln!(writer, "{}", msg)?;
+            } else {
+                writeln!(writer, "{}", message_id)?;
+            }
+        }
+        writer.flush()?;
+        Ok(event_ids.len())
+    }
+
+    /// Write the clusters in the current layer as a json object per line to `out`.
+    /// Each line is flushed to stream the output.
+    ///
+    /// Return the number of written clusters.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if it fails to write to `out`.
+    pub fn export_ndjson(&self, out: &mut dyn Write) -> Result<usize> {
+        let clusters = self
+            .rounds
+            .last()
+            .map_or(&[][..], |last| &last.clusters[..]);
+        for cid in clusters {
+            let mut labels: Vec<PatternId> = self.labels.labels_of(*cid).into_iter().collect();
+            labels.sort_unstable();
+            let line = ClusterExport {
+                cluster_id: *cid,
+                qualifier: self.clusters.qualifier(*cid).unwrap_or_default(),
+                size: self.clusters.size(*cid),
+                score: self.clusters.score(*cid),
+                signature: self.clusters.full_signature(*cid),
+                tags: self.clusters.tags(*cid),
+                labels: labels
+                    .iter()
+                    .map(|(tidb_id, rule_id)| format!("{}:{}", tidb_id, rule_id))
+                    .collect(),
+            };
+            serde_json::to_writer(&mut *out, &line)?;
+            writeln!(out)?;
+            out.flush()?;
+        }
+        Ok(clusters.len())
+    }
+
+    /// Write the `/filter` commands of the layers to `path`, which rebuild the current layer
+    /// with `--script`.
+    ///
+    /// Return the number of written commands.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if it fails to write the file.
+    pub fn export_script(&self, path: &str) -> Result<usize> {
+        let file = File::create(path).with_context(|| format!("cannot create {}", path))?;
+        let mut writer = BufWriter::new(file);
+        let mut count = 0;
+        for command in self.rounds.iter().filter_map(FilteredClusters::command) {
+            writeln!(writer, "{}", command)?;
+            count += 1;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Write the cluster labels and the event labels of the clusters in the current layer
+    /// to `path` in csv. Scores of cluster labels are normalized as shown.
+    ///
+    /// Return the number of written labels.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if it fails to write the file.
+    pub fn export_labels(&self, path: &str, cfg: &CliConf) -> Result<usize> {
+        let file = File::create(path).with_context(|| format!("cannot create {}", path))?;
+        let mut writer = BufWriter::new(file);
+        let mut count = 0;
+        writeln!(
+            writer,
+            "cluster_id,kind,label,name,score,count,cluster_size,tags"
+        )?;
+        let clusters = self
+            .rounds
+            .last()
+            .map_or(&[][..], |last| &last.clusters[..]);
+        for cid in clusters {
+            let size = self.clusters.size(*cid);
+            let tags = self.clusters.tags(*cid).join(";");
+            let dividend = self.label_score_divisor(*cid, cfg.label_score_norm());
+            if let Some(matched) = self.labels.get_representative_labels(*cid) {
+                for (tidb_id, rule_id, cnt, score) in matched {
+                    let score = if dividend > 0.0 {
+                        format!("{:.03}", f64::from(*score) / dividend)
+                    } else {
+                        String::new()
+                    };
+                    writeln!(
+                        writer,
+                        "{},cluster,{}:{},{},{},{},{},{}",
+                        cid,
+                        tidb_id,
+                        rule_id,
+                        csv_field(self.get_label_name(*tidb_id, *rule_id).unwrap_or_default()),
+                        score,
+                        cnt,
+                        size,
+                        csv_field(&tags)
+                    )?;
+                    count += 1;
+                }
+            }
+            if let Some(matched) = self.labels.get_event_labels(*cid) {
+                for ((tidb_id, rule_id), cnt) in matched {
+                    writeln!(
+                        writer,
+                        "{},event,{}:{},{},,{},{},{}",
+                        cid,
+                        tidb_id,
+                        rule_id,
+                        csv_field(self.get_label_name(tidb_id, rule_id).unwrap_or_default()),
+                        cnt,
+                        size,
+                        csv_field(&tags)
+                    )?;
+                    count += 1;
+                }
+            }
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Return the divisor of the cluster label scores of the cluster.
+    fn label_score_divisor(&self, cid: ClusterId, norm: LabelScoreNorm) -> f64 {
+        let to_f64 = |n: usize| f64::from(u32::try_from(n).unwrap_or_default());
+        match norm {
+            LabelScoreNorm::BySize => to_f64(self.clusters.size(cid)),
+            LabelScoreNorm::ByLabeledEvents => to_f64(self.labels.labeled_events_count(cid)),
+            LabelScoreNorm::Raw => 1.0,
+        }
+    }
+
+    /// Return the other clusters which have the highest-scoring representative label of the
+    /// cluster, sorted by score in descending order.
+    #[must_use]
+    pub fn related_by_top_label(&self, cid: ClusterId) -> Vec<ClusterId> {
+        let top = self
+            .labels
+            .get_representative_labels(cid)
+            .and_then(|labels| labels.iter().max_by(|a, b| a.3.total_cmp(&b.3)));
+        let (tidb_id, rule_id) = match top {
+            Some((tidb_id, rule_id, _, _)) => (*tidb_id, *rule_id),
+            None => return Vec::new(),
+        };
+        let mut related: Vec<ClusterId> = self
+            .labels
+            .find_clusters(tidb_id, rule_id)
+            .into_iter()
+            .filter(|c| *c != cid)
+            .collect();
+        related.sort_by(|a, b| {
+            let score = |c| self.clusters.score(c).unwrap_or_default();
+            score(*b).total_cmp(&score(*a))
+        });
+        related
+    }
+
+    #[must_use]
+    pub fn cluster_summary(&self, cid: ClusterId, score_digits: usize) -> Option<String> {
+        self.clusters.summary(cid, score_digits)
+    }
+
     #[must_use]
     pub fn find_cluster(&self, cid: ClusterId) -> Option<usize> {
         if let Some(last) = self.rounds.last() {
@@ -184,10 +1161,23 @@ impl TitleMatch {
         }
     }
 
-    pub fn filter_by(&mut self, ft: FilterType, op: FilterOp, value: &str) -> Option<usize> {
-        let clusters = self
-            .clusters
-            .filter_clusters(&self.rounds.last()?.clusters, ft, op, value);
+    /// Filter clusters by count, qualifier, regex, score or tag.
+    ///
+    /// Return the result of pushing the layer of filtered clusters. (see `push_layer`)
+    pub fn filter_by(
+        &mut self,
+        ft: FilterType,
+        op: FilterOp,
+        value: &str,
+        cfg: &CliConf,
+    ) -> PushResult {
+        // qualifiers and tags are changed by user
+        let key = match ft {
+            FilterType::Count => Some(format!("count {} {}", op, value)),
+            FilterType::Score => Some(format!("score {} {} {}", op, value, cfg.score_epsilon())),
+            _ => None,
+        };
+        let clusters = self.match_cached(key, |s| s.match_by(ft, op, value, cfg));
         info!(
             "filtering by \"{:?} {} {}\". {} clusters",
             ft,
@@ -195,106 +1185,396 @@ impl TitleMatch {
             value,
             clusters.len()
         );
+        let (op, pattern) = match ft {
+            FilterType::Qualifier | FilterType::Tag => (op, value.to_string()),
+            // negated patterns are kept as `<>` to export the layer as a command
+            FilterType::Regex => match value.strip_prefix('!') {
+                Some(p) => (FilterOp::NE, p.to_string()),
+                None => (op, value.to_string()),
+            },
+            _ => (op, format!("{} {}", op, value)),
+        };
+        self.push_layer(ft, op, pattern, clusters)
+    }
+
+    fn match_by(&self, ft: FilterType, op: FilterOp, value: &str, cfg: &CliConf) -> Vec<ClusterId> {
+        self.rounds.last().map_or_else(Vec::new, |last| {
+            self.clusters
+                .filter_clusters(&last.clusters, ft, op, value, cfg, &self.events)
+                .unwrap_or_else(|e| {
+                    eprintln!("Error: {}", e);
+                    Vec::new()
+                })
+        })
+    }
+
+    /// Whether no more filter layer can be pushed. (`max_filter_layers` in config)
+    #[must_use]
+    pub fn is_layers_full(&self) -> bool {
+        self.rounds.len() >= self.max_layers
+    }
+
+    /// Push a new filter layer of `clusters` unless it is empty, the layers are full or it
+    /// has the same clusters as the current layer. A regex layer of the same clusters, which
+    /// matches all clusters of the current layer, is kept to be pushed by `push_pending`.
+    fn push_layer(
+        &mut self,
+        filtertype: FilterType,
+        op: FilterOp,
+        pattern: String,
+        clusters: Vec<ClusterId>,
+    ) -> PushResult {
+        self.pending = None;
         if clusters.is_empty() {
-            None
+            return PushResult::Empty;
+        }
+        if self.is_layers_full() {
+            return PushResult::Full;
+        }
+        let layer = FilteredClusters {
+            id: self.next_layer_id,
+            filtertype,
+            op,
+            pattern,
+            clusters,
+        };
+        if !self.is_current_layer(&layer.clusters) {
+            PushResult::Pushed(self.push_new_layer(layer))
+        } else if filtertype == FilterType::Regex {
+            let msg = format!(
+                "filter matched all {} clusters; did you mean to narrow?",
+                layer.clusters.len()
+            );
+            warn!("{}", msg);
+            eprintln!("Warning: {}", msg);
+            self.pending = Some(layer);
+            PushResult::NeedsConfirm
         } else {
-            let cnt = clusters.len();
-            let pattern = if let FilterType::Qualifier = ft {
-                value.to_string()
+            PushResult::Same
+        }
+    }
+
+    /// Push the regex layer of the same clusters as the current layer, which is not pushed
+    /// by the last `push_layer` without confirmation.
+    ///
+    /// Return the number of clusters of the layer. `None` if there is no such layer, the
+    /// current layer is changed since or the layers are full.
+    pub fn push_pending(&mut self) -> Option<usize> {
+        let layer = self.pending.take()?;
+        if self.is_layers_full() || !self.is_current_layer(&layer.clusters) {
+            return None;
+        }
+        Some(self.push_new_layer(layer))
+    }
+
+    fn push_new_layer(&mut self, layer: FilteredClusters) -> usize {
+        let cnt = layer.clusters.len();
+        self.rounds.push(layer);
+        self.next_layer_id += 1;
+        self.popped.clear();
+        cnt
+    }
+
+    /// Return the clusters of the current layer matched by the filter `key`, found by `find`
+    /// if not cached. Only filters by immutable attributes are cached: count, score and
+    /// labels. `key` is `None` for the others. e.g. qualifiers, tags and regex of events
+    /// whose case sensitivity and delimiter can be changed.
+    fn match_cached<F>(&mut self, key: Option<String>, find: F) -> Vec<ClusterId>
+    where
+        F: FnOnce(&Self) -> Vec<ClusterId>,
+    {
+        let key = match (self.rounds.last(), key) {
+            (Some(last), Some(key)) => (last.id, key),
+            _ => return find(self),
+        };
+        if let Some(clusters) = self.filter_cache.get(&key) {
+            return clusters.clone();
+        }
+        let clusters = find(self);
+        if self.filter_cache.len() >= MAX_FILTER_CACHE {
+            self.filter_cache.clear();
+        }
+        self.filter_cache.insert(key, clusters.clone());
+        clusters
+    }
+
+    fn is_current_layer(&self, clusters: &[ClusterId]) -> bool {
+        self.rounds.last().is_some_and(|last| {
+            if last.clusters.len() != clusters.len() {
+                return false;
+            }
+            let current: HashSet<&ClusterId> = last.clusters.iter().collect();
+            clusters.iter().all(|cid| current.contains(cid))
+        })
+    }
+
+    /// Return the number of clusters which `/filter` with the same arguments would match,
+    /// without pushing a new layer.
+    #[must_use]
+    pub fn preview(
+        &self,
+        ft: FilterType,
+        op: FilterOp,
+        pattern: Option<&str>,
+        cfg: &CliConf,
+    ) -> usize {
+        self.match_filter(ft, op, pattern, cfg).len()
+    }
+
+    /// Return the number of clusters which all of `filters` would match.
+    #[must_use]
+    pub fn preview_all(&self, filters: &[Filter], cfg: &CliConf) -> usize {
+        self.match_all(filters, cfg).len()
+    }
+
+    /// Filter clusters matched by all of `filters` in a single layer.
+    /// `pattern` describes the filters in the layer.
+    ///
+    /// Return the result of pushing the layer of filtered clusters. (see `push_layer`)
+    pub fn filter_by_all(
+        &mut self,
+        filters: &[Filter],
+        pattern: &str,
+        cfg: &CliConf,
+    ) -> PushResult {
+        let found = self.match_all(filters, cfg);
+        info!("filtering by \"{}\". {} clusters", pattern, found.len());
+        self.push_layer(FilterType::And, FilterOp::EQ, pattern.to_string(), found)
+    }
+
+    fn match_all(&self, filters: &[Filter], cfg: &CliConf) -> Vec<ClusterId> {
+        let mut found: Option<Vec<ClusterId>> = None;
+        for (ft, op, pattern) in filters {
+            let matched = self.match_filter(*ft, *op, pattern.as_deref(), cfg);
+            found = Some(if let Some(mut found) = found {
+                let matched: HashSet<ClusterId> = matched.into_iter().collect();
+                found.retain(|cid| matched.contains(cid));
+                found
             } else {
-                format!("{} {}", op, value)
-            };
-            self.rounds.push(FilteredClusters {
-                filtertype: ft,
-                op,
-                pattern,
-                clusters,
+                matched
             });
-            Some(cnt)
+        }
+        found.unwrap_or_default()
+    }
+
+    /// Return the clusters in the current layer matched by the filter.
+    fn match_filter(
+        &self,
+        ft: FilterType,
+        op: FilterOp,
+        pattern: Option<&str>,
+        cfg: &CliConf,
+    ) -> Vec<ClusterId> {
+        match (ft, pattern) {
+            (
+                FilterType::Count
+                | FilterType::Qualifier
+                | FilterType::Regex
+                | FilterType::Score
+                | FilterType::Tag,
+                Some(s),
+            ) => self.match_by(ft, op, s, cfg),
+            (FilterType::Label, _) => self.match_by_label(op, pattern),
+            (FilterType::LabelAll, Some(s)) => {
+                let patterns = parse_pattern_ids(s);
+                if patterns.is_empty() {
+                    Vec::new()
+                } else {
+                    self.match_by_labels_intersection(&patterns)
+                }
+            }
+            (FilterType::LabelCoverage, Some(s)) => parse_label_coverage(s)
+                .map_or_else(Vec::new, |(label, frac)| {
+                    self.match_by_label_coverage(label, op, frac, cfg)
+                }),
+            (FilterType::LabelCount, Some(s)) => s
+                .parse::<usize>()
+                .map_or_else(|_| Vec::new(), |n| self.match_by_label_count(op, n)),
+            _ => Vec::new(),
         }
     }
 
     /// Filter clusters with label.
     /// if `pattern_id` is none, then all labels.
+    /// if `op` is `FilterOp::NE`, the clusters which do not have the label.
     ///
-    /// Return the number of filtered clusters
+    /// Return the result of pushing the layer of filtered clusters. (see `push_layer`)
     pub fn filter_by_label(
         &mut self,
         ft: FilterType,
         op: FilterOp,
         pattern_id: Option<&str>,
-    ) -> Option<usize> {
+    ) -> PushResult {
+        let key = Some(format!("label {} {:?}", op, pattern_id));
+        let found = self.match_cached(key, |s| s.match_by_label(op, pattern_id));
+        let pattern = if let Some(v) = pattern_id {
+            v.to_string()
+        } else {
+            String::from("All")
+        };
+        self.push_layer(ft, op, pattern, found)
+    }
+
+    fn match_by_label(&self, op: FilterOp, pattern_id: Option<&str>) -> Vec<ClusterId> {
         let (tidb_id, rule_id) = parse_pattern_id(pattern_id);
-        let last = &self.rounds.last()?.clusters;
-        let mut found = self.labels.find_clusters(tidb_id, rule_id);
-        found.retain(|cluster_id| last.contains(cluster_id));
-        if found.is_empty() {
-            None
+        let last = match self.rounds.last() {
+            Some(last) => &last.clusters,
+            None => return Vec::new(),
+        };
+        let labeled = self.labels.find_clusters(tidb_id, rule_id);
+        if op == FilterOp::NE {
+            last.iter()
+                .filter(|cluster_id| labeled.binary_search(cluster_id).is_err())
+                .copied()
+                .collect()
         } else {
-            let cnt = found.len();
-            let pattern = if let Some(v) = pattern_id {
-                v.to_string()
-            } else {
-                String::from("All")
-            };
+            labeled
+                .into_iter()
+                .filter(|cluster_id| last.contains(cluster_id))
+                .collect()
+        }
+    }
 
-            self.rounds.push(FilteredClusters {
-                filtertype: ft,
-                op,
-                pattern,
-                clusters: found,
-            });
-            Some(cnt)
+    /// Filter clusters which have all of the specified labels.
+    /// 0 in `TidbId` or `RuleId` matches any id.
+    ///
+    /// Return the result of pushing the layer of filtered clusters. (see `push_layer`)
+    pub fn filter_by_labels_intersection(&mut self, patterns: &[PatternId]) -> PushResult {
+        if patterns.is_empty() {
+            return PushResult::Empty;
         }
+        let key = Some(format!("label-all {:?}", patterns));
+        let found = self.match_cached(key, |s| s.match_by_labels_intersection(patterns));
+        info!(
+            "filtering by labels {:?}. {} clusters",
+            patterns,
+            found.len()
+        );
+        let pattern = patterns
+            .iter()
+            .map(|(tidb_id, rule_id)| format!("{}:{}", tidb_id, rule_id))
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.push_layer(FilterType::LabelAll, FilterOp::EQ, pattern, found)
     }
 
-    pub fn filter_by_regex(&mut self, pattern: &str) -> Option<usize> {
-        let last = self.rounds.last()?;
+    fn match_by_labels_intersection(&self, patterns: &[PatternId]) -> Vec<ClusterId> {
+        let mut found = self
+            .rounds
+            .last()
+            .map_or_else(Vec::new, |last| last.clusters.clone());
+        for (tidb_id, rule_id) in patterns {
+            let clusters = self.labels.find_clusters(*tidb_id, *rule_id);
+            found.retain(|cluster_id| clusters.binary_search(cluster_id).is_ok());
+        }
+        found
+    }
 
-        /* ! => negation (trick!!!) */
-        let mut negate: bool = false;
-        let pattern = if pattern.starts_with('!') {
-            if pattern.len() == 1 {
-                return None;
-            }
-            negate = true;
-            pattern.get(1..).unwrap_or(pattern)
-        } else {
-            pattern
-        };
+    /// Filter clusters by the number of distinct labels. Clusters without labels have 0.
+    ///
+    /// Return the result of pushing the layer of filtered clusters. (see `push_layer`)
+    pub fn filter_by_label_count(&mut self, op: FilterOp, n: usize) -> PushResult {
+        let key = Some(format!("labelcount {} {}", op, n));
+        let clusters = self.match_cached(key, |s| s.match_by_label_count(op, n));
+        let pattern = format!("{} {}", op, n);
+        info!(
+            "filtering by label count \"{}\". {} clusters",
+            pattern,
+            clusters.len()
+        );
+        self.push_layer(FilterType::LabelCount, op, pattern, clusters)
+    }
 
-        match self
-            .clusters
-            .regex_match(&last.clusters, pattern, &self.events)
-        {
-            Ok(mut clusters) => {
-                if negate {
-                    clusters = last
-                        .clusters
-                        .iter()
-                        .filter(|cid| !clusters.contains(cid))
-                        .copied()
-                        .collect();
+    fn match_by_label_count(&self, op: FilterOp, n: usize) -> Vec<ClusterId> {
+        let last = match self.rounds.last() {
+            Some(last) => &last.clusters,
+            None => return Vec::new(),
+        };
+        last.iter()
+            .filter(|cid| {
+                let count = self.labels.label_count(**cid);
+                match op {
+                    FilterOp::L => count < n,
+                    FilterOp::G => count > n,
+                    FilterOp::LE => count <= n,
+                    FilterOp::GE => count >= n,
+                    FilterOp::EQ => count == n,
+                    FilterOp::NE => count != n,
                 }
+            })
+            .copied()
+            .collect()
+    }
 
-                if clusters.is_empty() {
-                    None
-                } else {
-                    let cnt = clusters.len();
-                    self.rounds.push(FilteredClusters {
-                        filtertype: FilterType::Regex,
-                        op: FilterOp::EQ,
-                        pattern: pattern.to_string(),
-                        clusters,
-                    });
-                    Some(cnt)
+    /// Filter clusters by the fraction of events labeled by `pattern` out of the cluster size.
+    /// `=` and `<>` compare the fraction within the score tolerance of `cfg`.
+    ///
+    /// Return the result of pushing the layer of filtered clusters. (see `push_layer`)
+    pub fn filter_by_label_coverage(
+        &mut self,
+        pattern: PatternId,
+        op: FilterOp,
+        frac: f64,
+        cfg: &CliConf,
+    ) -> PushResult {
+        let key = Some(format!(
+            "labelcov {:?} {} {} {}",
+            pattern,
+            op,
+            frac,
+            cfg.score_epsilon()
+        ));
+        let clusters =
+            self.match_cached(key, |s| s.match_by_label_coverage(pattern, op, frac, cfg));
+        let pattern = format!("{}:{} {} {}", pattern.0, pattern.1, op, frac);
+        info!(
+            "filtering by label coverage \"{}\". {} clusters",
+            pattern,
+            clusters.len()
+        );
+        self.push_layer(FilterType::LabelCoverage, op, pattern, clusters)
+    }
+
+    fn match_by_label_coverage(
+        &self,
+        pattern: PatternId,
+        op: FilterOp,
+        frac: f64,
+        cfg: &CliConf,
+    ) -> Vec<ClusterId> {
+        let epsilon = f64::from(cfg.score_epsilon());
+        let last = match self.rounds.last() {
+            Some(last) => &last.clusters,
+            None => return Vec::new(),
+        };
+        last.iter()
+            .filter(|cid| {
+                let size = u32::try_from(self.clusters.size(**cid)).unwrap_or_default();
+                if size == 0 {
+                    return false;
                 }
-            }
-            Err(e) => {
-                eprintln!("Error: {}", e);
-                None
-            }
-        }
+                let count =
+                    u32::try_from(self.labels.events_count_of(**cid, pattern)).unwrap_or_default();
+                let coverage = f64::from(count) / f64::from(size);
+                match op {
+                    FilterOp::L => coverage < frac,
+                    FilterOp::G => coverage > frac,
+                    FilterOp::LE => coverage <= frac,
+                    FilterOp::GE => coverage >= frac,
+                    FilterOp::EQ => (coverage - frac).abs() <= epsilon,
+                    FilterOp::NE => (coverage - frac).abs() > epsilon,
+                }
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Clear the event filters of the cluster at `idx` in the current layer.
+    ///
+    /// Return false if the cluster is not found or it has no event filter.
+    pub fn clear_event_filter(&mut self, idx: usize) -> bool {
+        self.cluster_id(idx)
+            .is_some_and(|cid| self.clusters.clear_filter(cid))
     }
 
     /// # Errors
@@ -305,6 +1585,7 @@ impl TitleMatch {
         ft: FilterType,
         pattern: Option<&str>,
         ticks: &Option<usize>,
+        cfg: &CliConf,
     ) -> Result<usize> {
         let mut filtered_events_count = 0;
         if let Some(index) = ticks {
@@ -314,12 +1595,15 @@ impl TitleMatch {
                 .and_then(|last| last.clusters.get(*index))
                 .ok_or_else(|| anyhow!("Cluster {} not found", index))?;
             match ft {
-                FilterType::NoFilter => self.clusters.clear_filter(*cluster_id),
+                FilterType::NoFilter => {
+                    self.clusters.clear_filter(*cluster_id);
+                }
                 FilterType::Regex => {
                     if let Some(pattern) = pattern {
                         match self.clusters.regex_match_in_this_cluster(
                             *cluster_id,
                             pattern,
+                            cfg.is_regex_case_on(),
                             &self.events,
                         ) {
                             Err(e) => eprintln!("Error: {}", e),
@@ -337,6 +1621,9 @@ impl TitleMatch {
         }
         Ok(filtered_events_count)
     }
+    /// Pop the current filter layer.
+    /// Qualifiers and event filters set on the clusters are kept. (see `Members`)
+    ///
     /// # Errors
     ///
     /// Will return `Err` if a try to remove on an empty filter
@@ -344,11 +1631,117 @@ impl TitleMatch {
         if self.rounds.is_empty() {
             Err(anyhow!("Failed to remove the filtered clusters."))
         } else {
-            let _r = self.rounds.pop();
+            if let Some(layer) = self.rounds.pop() {
+                self.popped.push(layer);
+            }
+            let layers: HashSet<usize> = self.rounds.iter().map(|r| r.id).collect();
+            self.filter_cache.retain(|(id, _), _| layers.contains(id));
             Ok(())
         }
     }
 
+    /// Push back the layer removed last by `remove_filter` without filtering again.
+    ///
+    /// Return the number of clusters of the layer. `None` if there is no layer to restore or
+    /// the layers are full.
+    pub fn restore_filter(&mut self) -> Option<usize> {
+        if self.is_layers_full() {
+            return None;
+        }
+        let layer = self.popped.pop()?;
+        let cnt = layer.clusters.len();
+        self.rounds.push(layer);
+        Some(cnt)
+    }
+
+    /// Print the clusters triaged and tagged in this session, and whether they are saved.
+    pub fn show_session_summary(&self) {
+        let (triaged, tags, tagged) = self.clusters.triage_summary();
+        let breakdown = triaged
+            .iter()
+            .map(|(qualifier, count)| format!("{} {}", count, qualifier))
+            .collect::<Vec<_>>()
+            .join(", ");
+        println!("\nSession summary");
+        if breakdown.is_empty() {
+            println!("{:>6} clusters triaged", 0);
+        } else {
+            println!(
+                "{:>6} clusters triaged: {}",
+                triaged.values().sum::<usize>(),
+                breakdown
+            );
+        }
+        println!("{:>6} tags on {} clusters", tags, tagged);
+        let unsaved = self.unsaved_count();
+        if unsaved == 0 {
+            println!("       all changes saved");
+        } else {
+            println!("{:>6} changes not saved", unsaved);
+        }
+    }
+
+    #[must_use]
+    pub fn unsaved_count(&self) -> usize {
+        self.clusters.unsaved_count()
+    }
+
+    /// Save the qualifiers changed by user to `path` in json format.
+    ///
+    /// Return the number of saved clusters.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the file exists and `force` is not set, or if it fails to write the file.
+    pub fn save(&mut self, path: &str, force: bool) -> Result<usize> {
+        if !force && std::path::Path::new(path).exists() {
+            return Err(anyhow!("{} already exists. use \"/save force\"", path));
+        }
+        let changed: Vec<SavedQualifier> = self
+            .clusters
+            .changed_qualifiers()
+            .into_iter()
+            .map(|(cluster_id, qualifier, tags)| SavedQualifier {
+                cluster_id,
+                qualifier,
+                tags,
+            })
+            .collect();
+        let file = File::create(path).with_context(|| format!("cannot create {}", path))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &changed)?;
+        self.clusters.mark_saved();
+        info!("{} qualifiers saved to {}", changed.len(), path);
+        Ok(changed.len())
+    }
+
+    /// Add or remove the tag of the cluster at `idx` in the current layer.
+    /// Return false if the cluster is not found or the tag is unchanged.
+    pub fn set_tag(&mut self, idx: usize, tag: &str, add: bool) -> bool {
+        let cid = if let Some(cid) = self.cluster_id(idx) {
+            cid
+        } else {
+            println!("Cluster not found!\n");
+            return false;
+        };
+        if self.clusters.set_tag(cid, tag, add) {
+            println!(
+                "cluster #{} {} tag {}",
+                cid,
+                if add { "added" } else { "removed" },
+                tag
+            );
+            true
+        } else {
+            println!(
+                "cluster #{} {} tag {}",
+                cid,
+                if add { "already has" } else { "does not have" },
+                tag
+            );
+            false
+        }
+    }
+
     pub fn set_qualifier(&mut self, idx: usize, qualifier: &str, all: bool) -> Option<usize> {
         let last = self.rounds.last()?;
 
@@ -378,6 +1771,114 @@ impl TitleMatch {
     }
 }
 
+const COMPARE_COLUMN_WIDTH: usize = 30;
+
+fn print_columns(title: &str, a: ClusterId, b: ClusterId, columns: &[Vec<String>; 3]) {
+    println!("\n{}", bold!(title));
+    println!(
+        "{:<width$} {:<width$} {:<width$}",
+        format!("only #{} ({})", a, columns[0].len()),
+        format!("shared ({})", columns[1].len()),
+        format!("only #{} ({})", b, columns[2].len()),
+        width = COMPARE_COLUMN_WIDTH
+    );
+    let rows = columns.iter().map(Vec::len).max().unwrap_or_default();
+    for i in 0..rows {
+        let cell = |col: &Vec<String>| {
+            col.get(i)
+                .map(|v| {
+                    if v.chars().count() > COMPARE_COLUMN_WIDTH {
+                        let mut v: String = v.chars().take(COMPARE_COLUMN_WIDTH - 3).collect();
+                        v.push_str("...");
+                        v
+                    } else {
+                        v.clone()
+                    }
+                })
+                .unwrap_or_default()
+        };
+        println!(
+            "{:<width$} {:<width$} {:<width$}",
+            cell(&columns[0]),
+            cell(&columns[1]),
+            cell(&columns[2]),
+            width = COMPARE_COLUMN_WIDTH
+        );
+    }
+}
+
+/// Quote the csv field if it has commas, quotes or newlines.
+fn csv_field(s: &str) -> Cow<'_, str> {
+    if s.contains(&[',', '"', '\n'][..]) {
+        Cow::Owned(format!("\"{}\"", s.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+/// Warn about the events which are both in outliers and clusters. The clustering result is
+/// inconsistent if any.
+fn warn_outlier_overlap(clusters: &Clusters) {
+    let overlap = clusters.outlier_overlap();
+    if !overlap.is_empty() {
+        let msg = format!(
+            "{} events of outliers are also in clusters. {:?}",
+            overlap.len(),
+            overlap
+                .iter()
+                .take(MAX_OUTLIER_OVERLAP_DISPLAY)
+                .collect::<Vec<_>>()
+        );
+        warn!("{}", msg);
+        eprintln!("Warning: {}", msg);
+    }
+}
+
+/// Warn if no tokens are extracted or some clusters have no loaded events.
+fn warn_if_incomplete(clusters: &Clusters, events: &Events) {
+    if clusters.token_count() == 0 && !events.is_empty() {
+        let msg = "no tokens are extracted from the events. check the weight of columns in config.";
+        warn!("{}", msg);
+        eprintln!("Warning: {}", msg);
+    }
+    let unloaded = clusters.unloaded_clusters(events);
+    if !unloaded.is_empty() {
+        let msg = format!(
+            "{} clusters have no loaded events. {:?}",
+            unloaded.len(),
+            unloaded
+                .iter()
+                .take(MAX_UNLOADED_CLUSTERS_DISPLAY)
+                .collect::<Vec<_>>()
+        );
+        warn!("{}", msg);
+        eprintln!("Warning: {}", msg);
+    }
+}
+
+/// Parse the argument of `/filter labelcov`. e.g. "5:12 0.5". The fraction is in `0.0..=1.0`.
+#[must_use]
+pub fn parse_label_coverage(s: &str) -> Option<(PatternId, f64)> {
+    let (label, frac) = s.split_once(' ')?;
+    let frac = frac
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|frac| (0.0..=1.0).contains(frac))?;
+    Some((*parse_pattern_ids(label).first()?, frac))
+}
+
+/// Parse whitespace separated pattern ids. e.g. "5:12 7:3"
+#[must_use]
+pub fn parse_pattern_ids(pattern_ids: &str) -> Vec<PatternId> {
+    pattern_ids
+        .split_whitespace()
+        .map(|id| parse_pattern_id(Some(id)))
+        .collect()
+}
+
+/// Parse "<tidb-id>:<rule-id>". A missing or invalid id is 0, which matches any id.
+/// e.g. "5" is any rule of tidb 5, ":12" is rule 12 of any tidb.
 fn parse_pattern_id(pattern_id: Option<&str>) -> (u32, u32) {
     let mut tidb_id: TidbId = 0;
     let mut rule_id: RuleId = 0;
@@ -396,3 +1897,211 @@ fn parse_pattern_id(pattern_id: Option<&str>) -> (u32, u32) {
     }
     (tidb_id, rule_id)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ConfigType;
+
+    /// Load three clusters of six events and an outlier event, written in a temporary directory
+    /// which is removed after loading.
+    fn title_match(name: &str) -> TitleMatch {
+        let dir = std::env::temp_dir().join(format!("labeler-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(dir.join("tidb")).expect("writable temp dir");
+        let files = [
+            (
+                "events.csv",
+                "e1,GET /index.php?id=1 union select password from users\n\
+                 e2,GET /index.php?id=2 union select name from admins\n\
+                 e3,GET /index.php?id=3 union all select\n\
+                 e4,POST /login.php user=admin pass=secret\n\
+                 e5,POST /login.php user=guest\n\
+                 e6,GET /favicon.ico\n\
+                 e7,HEAD /robots.txt\n",
+            ),
+            (
+                "clusters.json",
+                r#"{"detector_id":1,"events_count":6,"clusters_count":3,"outlier_count":1,
+                "clusters":[
+                {"cluster_id":1,"cluster_size":3,"signature":"union select","score":0.9,
+                 "events":["e1","e2","e3"]},
+                {"cluster_id":2,"cluster_size":2,"signature":"login.php","score":0.5,
+                 "events":["e4","e5"]},
+                {"cluster_id":3,"cluster_size":1,"signature":null,"score":0.1,"events":["e6"]}],
+                "outliers":["e7,HEAD /robots.txt"]}"#,
+            ),
+            (
+                "labels.json",
+                r#"{"representative_labels":1,"event_labels":2,"representative":[[1,[[10,5,2,1.5]]]],
+                "events":[[1,[["e1",[[10,5,0.7]]],["e2",[[10,5,0.8]]]]]]}"#,
+            ),
+        ];
+        for (file, content) in &files {
+            std::fs::write(dir.join(file), content).expect("writable temp dir");
+        }
+        let cfg = Config::from_dir(&dir.display().to_string()).expect("valid dataset");
+        let tm = TitleMatch::new(&cfg, false, false).expect("valid dataset");
+        std::fs::remove_dir_all(&dir).expect("removable temp dir");
+        tm
+    }
+
+    /// Write into a buffer with `write`, and return it as a string.
+    fn capture<F>(write: F) -> String
+    where
+        F: FnOnce(&mut dyn Write) -> std::io::Result<()>,
+    {
+        let mut out = Vec::new();
+        write(&mut out).expect("writable buffer");
+        String::from_utf8(out).expect("utf-8 output")
+    }
+
+    #[test]
+    fn filtered_event_ids_with_and_without_event_filter() {
+        let mut tm = title_match("filtered_event_ids");
+        let cfg = CliConf::default();
+        assert_eq!(
+            tm.filtered_event_ids(0),
+            Some(&[String::from("e1"), String::from("e2"), String::from("e3")][..])
+        );
+        assert_eq!(
+            tm.filter_event(FilterType::Regex, Some("union select"), &Some(0), &cfg)
+                .ok(),
+            Some(2)
+        );
+        assert_eq!(
+            tm.filtered_event_ids(0),
+            Some(&[String::from("e1"), String::from("e2")][..])
+        );
+        assert!(tm.clear_event_filter(0));
+        assert_eq!(tm.filtered_event_ids(0).map(<[_]>::len), Some(3));
+        assert_eq!(tm.filtered_event_ids(9), None);
+    }
+
+    #[test]
+    fn user_edits_are_kept_across_layers() {
+        let mut tm = title_match("user_edits");
+        let cfg = CliConf::default();
+        assert_eq!(
+            tm.filter_by(FilterType::Score, FilterOp::G, "0.8", &cfg),
+            PushResult::Pushed(1)
+        );
+        assert_eq!(
+            tm.filter_event(FilterType::Regex, Some("!admins"), &Some(0), &cfg)
+                .ok(),
+            Some(2)
+        );
+        assert_eq!(tm.set_qualifier(0, "benign", false), Some(1));
+        assert!(tm.remove_filter().is_ok());
+
+        // cluster 1 is also the first cluster of the base layer
+        assert_eq!(tm.cluster_id(0), Some(1));
+        assert_eq!(tm.filtered_event_ids(0).map(<[_]>::len), Some(2));
+        assert_eq!(tm.clusters.qualifier(1), Some(Qualifier::Benign));
+
+        // the same filter again shows the same event filter, not reset
+        assert_eq!(
+            tm.filter_by(FilterType::Score, FilterOp::G, "0.8", &cfg),
+            PushResult::Pushed(1)
+        );
+        assert_eq!(tm.filtered_event_ids(0).map(<[_]>::len), Some(2));
+    }
+
+    #[test]
+    fn write_statistics_of_dataset() {
+        let tm = title_match("statistics");
+        assert_eq!(
+            capture(|out| tm.write_statistics(out)),
+            "     4 clusters\n     1 labeled clusters\n     2 labeled events\n     \
+             1 representatives\n     2 clusters of score >= 0.5 (scores 0.100 - 0.900)\n"
+        );
+    }
+
+    #[test]
+    fn write_cluster_after_filtering() {
+        let mut tm = title_match("write_cluster");
+        let cfg = CliConf::default();
+        assert_eq!(
+            tm.filter_by(FilterType::Count, FilterOp::EQ, "2", &cfg),
+            PushResult::Pushed(1)
+        );
+        let output = capture(|out| tm.write_cluster(out, 0, &cfg, false));
+        assert!(output.starts_with("[0] cluster 2, "), "{}", output);
+        assert!(!output.contains('\u{1b}'), "{}", output);
+        assert!(output.contains("signature = login.php\n"), "{}", output);
+        assert!(
+            output.contains("e4,POST /login.php user=admin pass=secret\n"),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains("e5,POST /login.php user=guest\n"),
+            "{}",
+            output
+        );
+        assert!(!output.contains("e1,"), "{}", output);
+        assert_eq!(capture(|out| tm.write_cluster(out, 1, &cfg, false)), "");
+    }
+
+    #[test]
+    fn push_pending_layer_of_same_clusters() {
+        let mut tm = title_match("push_pending");
+        let cfg = CliConf::default();
+        assert_eq!(tm.push_pending(), None);
+        assert_eq!(
+            tm.filter_by(FilterType::Regex, FilterOp::EQ, "php", &cfg),
+            PushResult::Pushed(2)
+        );
+        assert_eq!(
+            tm.filter_by(FilterType::Regex, FilterOp::EQ, "/", &cfg),
+            PushResult::NeedsConfirm
+        );
+        assert_eq!(tm.rounds.len(), 2);
+        assert_eq!(tm.push_pending(), Some(2));
+        assert_eq!(tm.rounds.len(), 3);
+        assert_eq!(tm.rounds.last().map(|r| r.pattern.as_str()), Some("/"));
+        assert_eq!(tm.push_pending(), None);
+
+        // the pending layer is dropped by the next filter
+        assert_eq!(
+            tm.filter_by(FilterType::Regex, FilterOp::EQ, "/", &cfg),
+            PushResult::NeedsConfirm
+        );
+        assert_eq!(
+            tm.filter_by(FilterType::Count, FilterOp::EQ, "2", &cfg),
+            PushResult::Pushed(1)
+        );
+        assert_eq!(tm.push_pending(), None);
+
+        // only a regex layer of the same clusters can be confirmed
+        assert_eq!(
+            tm.filter_by(FilterType::Count, FilterOp::EQ, "2", &cfg),
+            PushResult::Same
+        );
+        assert_eq!(tm.push_pending(), None);
+        assert_eq!(
+            tm.filter_by(FilterType::Regex, FilterOp::EQ, "xyz", &cfg),
+            PushResult::Empty
+        );
+    }
+
+    #[test]
+    fn label_coverage_within_score_epsilon() {
+        let tm = title_match("label_coverage");
+        let mut cfg = CliConf::default();
+        let coverage =
+            |op, cfg: &CliConf| tm.preview(FilterType::LabelCoverage, op, Some("10:5 0.667"), cfg);
+        assert_eq!(coverage(FilterOp::EQ, &cfg), 1);
+        assert_eq!(coverage(FilterOp::NE, &cfg), 3);
+        cfg.set(ConfigType::ScoreEpsilon(0.0));
+        assert_eq!(coverage(FilterOp::EQ, &cfg), 0);
+        assert_eq!(coverage(FilterOp::NE, &cfg), 4);
+    }
+
+    #[test]
+    fn pattern_id_of_any_tidb() {
+        assert_eq!(parse_pattern_id(Some(":12")), (0, 12));
+        assert_eq!(parse_pattern_id(Some("5")), (5, 0));
+        assert_eq!(parse_pattern_id(Some("5:12")), (5, 12));
+        assert_eq!(parse_pattern_ids("5:12 :3"), vec![(5, 12), (0, 3)]);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::config::{ColumnType, NormalizerKind};
+use crate::EventType;
 use percent_encoding::percent_decode_str;
 
 const OPTION_URL_DECODE: bool = false;
@@ -10,8 +12,24 @@ const OPTION_REMOVE_HEXCODE: bool = true;
 const OPTION_HEXCODE_MIN_LENGTH: usize = 20;
 const OPTION_REMOVE_DOT_DIGIT: bool = true;
 
-// characters treated as token
-const TOKEN_CHARS: [char; 4] = ['.', '_', '-', '@'];
+/// Characters treated as a part of tokens besides alphanumerics, if `token_chars` is not
+/// set in config. (`csv` and `packet` events)
+pub const DEFAULT_TOKEN_CHARS: [char; 4] = ['.', '_', '-', '@'];
+/// Token characters of `log` events. URLs and paths such as `/cgi-bin/test.cgi` are kept
+/// in one token.
+pub const LOG_TOKEN_CHARS: [char; 6] = ['.', '_', '-', '@', '/', ':'];
+
+/// Default token characters by event type.
+///
+/// * `log`: `LOG_TOKEN_CHARS`
+/// * `csv`, `packet`: `DEFAULT_TOKEN_CHARS`
+#[must_use]
+pub fn default_token_chars(event_type: EventType) -> &'static [char] {
+    match event_type {
+        EventType::Log => &LOG_TOKEN_CHARS,
+        EventType::Csv | EventType::Packet => &DEFAULT_TOKEN_CHARS,
+    }
+}
 
 /*
 const TOKEN_DELIMITERS: [char; 28] = [
@@ -20,8 +38,99 @@ const TOKEN_DELIMITERS: [char; 28] = [
 ];
 */
 
+/// Normalize a token extracted by `extract_tokens`.
+pub trait TokenNormalizer {
+    /// Return the normalized token, or `None` to drop it.
+    fn normalize(&self, token: String) -> Option<String>;
+}
+
+/// Keep tokens as they are.
+pub struct DefaultNormalizer;
+
+impl TokenNormalizer for DefaultNormalizer {
+    fn normalize(&self, token: String) -> Option<String> {
+        Some(token)
+    }
+}
+
+/// Remove leading and trailing dots(.) of tokens.
+pub struct TrimDotsNormalizer;
+
+impl TokenNormalizer for TrimDotsNormalizer {
+    fn normalize(&self, token: String) -> Option<String> {
+        let trimmed = token.trim_matches('.');
+        if trimmed.len() < OPTION_TOKEN_MIN_LENGTH {
+            None
+        } else if trimmed.len() == token.len() {
+            Some(token)
+        } else {
+            Some(trimmed.to_string())
+        }
+    }
+}
+
+#[must_use]
+pub fn normalizer(kind: NormalizerKind) -> Box<dyn TokenNormalizer> {
+    match kind {
+        NormalizerKind::Default => Box::new(DefaultNormalizer),
+        NormalizerKind::TrimDots => Box::new(TrimDotsNormalizer),
+    }
+}
+
+/// Extract tokens from a column value by its data type.
+/// * `Ipaddr`: the whole address is a token.
+/// * `Datetime`: no tokens.
+/// * others: split by `extract_tokens`.
+#[must_use]
+pub fn extract_column_tokens(
+    s: &str,
+    data_type: ColumnType,
+    token_chars: &[char],
+    normalizer: &dyn TokenNormalizer,
+) -> Vec<String> {
+    match data_type {
+        ColumnType::Ipaddr => {
+            let s = s.trim();
+            if s.is_empty() {
+                Vec::new()
+            } else {
+                vec![s.to_string()]
+            }
+        }
+        ColumnType::Datetime => Vec::new(),
+        _ => extract_tokens(s, token_chars, normalizer),
+    }
+}
+
+/// Split `s` into the runs of token characters and the others.
+/// Return (whether the run is a word, the run) in order.
+#[must_use]
+pub fn split_words<'a>(s: &'a str, token_chars: &[char]) -> Vec<(bool, &'a str)> {
+    let is_token_char = |c: char| c.is_alphanumeric() || token_chars.contains(&c);
+    let mut runs = Vec::new();
+    let mut begin = 0;
+    let mut in_word = false;
+    for (idx, c) in s.char_indices() {
+        if is_token_char(c) != in_word {
+            if idx > begin {
+                runs.push((in_word, &s[begin..idx]));
+            }
+            begin = idx;
+            in_word = !in_word;
+        }
+    }
+    if s.len() > begin {
+        runs.push((in_word, &s[begin..]));
+    }
+    runs
+}
+
 #[must_use]
-pub fn extract_tokens(s: &str) -> Vec<String> {
+pub fn extract_tokens(
+    s: &str,
+    token_chars: &[char],
+    normalizer: &dyn TokenNormalizer,
+) -> Vec<String> {
     let mut pairs: Vec<(usize, usize)> = Vec::new();
     let mut begin: usize;
     let mut end: usize;
@@ -34,7 +143,7 @@ pub fn extract_tokens(s: &str) -> Vec<String> {
 
         loop {
             if let Some((idx, c)) = chs.next() {
-                if c.is_alphanumeric() || TOKEN_CHARS.contains(&c) {
+                if c.is_alphanumeric() || token_chars.contains(&c) {
                     begin = idx;
                     break;
                 }
@@ -48,7 +157,7 @@ pub fn extract_tokens(s: &str) -> Vec<String> {
             loop {
                 if let Some((idx, c)) = chs.next() {
                     end = idx;
-                    if c.is_alphanumeric() || TOKEN_CHARS.contains(&c) {
+                    if c.is_alphanumeric() || token_chars.contains(&c) {
                         continue;
                     }
                     break;
@@ -106,10 +215,42 @@ pub fn extract_tokens(s: &str) -> Vec<String> {
                 continue;
             }
 
-            // TODO:
-            // - remove leading and trailing dot(.)
+            if let Some(token) = normalizer.normalize(token) {
+                v.push(token);
+            }
+        }
+    }
+    v
+}
 
-            v.push(token);
+/// Extract email addresses in `s` as whole tokens. The domain of the address is also
+/// a token if `with_domain` is set. Tokens are in lowercase.
+#[must_use]
+pub fn extract_emails(s: &str, with_domain: bool) -> Vec<String> {
+    let is_local_char = |c: char| c.is_ascii_alphanumeric() || "._%+-".contains(c);
+    let is_domain_char = |c: char| c.is_ascii_alphanumeric() || c == '.' || c == '-';
+    let mut v = Vec::new();
+    for (at, _) in s.match_indices('@') {
+        let local_begin = s[..at]
+            .char_indices()
+            .rev()
+            .find(|(_, c)| !is_local_char(*c))
+            .map_or(0, |(idx, c)| idx + c.len_utf8());
+        let local = s[local_begin..at].trim_start_matches('.');
+        let domain_end = s[at + 1..]
+            .find(|c: char| !is_domain_char(c))
+            .map_or(s.len(), |idx| at + 1 + idx);
+        let domain = s[at + 1..domain_end].trim_end_matches(&['.', '-'][..]);
+        let valid_tld = domain.rsplit_once('.').is_some_and(|(name, tld)| {
+            !name.is_empty() && tld.len() >= 2 && tld.chars().all(|c| c.is_ascii_alphabetic())
+        });
+        if local.is_empty() || !valid_tld {
+            continue;
+        }
+        let domain = domain.to_lowercase();
+        v.push(format!("{}@{}", local.to_lowercase(), domain));
+        if with_domain {
+            v.push(domain);
         }
     }
     v
@@ -153,3 +294,52 @@ fn check_dotdigit(x: &str) -> bool {
         return true;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_chars_join_tokens() {
+        let normalizer = normalizer(NormalizerKind::Default);
+        assert_eq!(
+            extract_tokens("abc:def", &DEFAULT_TOKEN_CHARS, normalizer.as_ref()),
+            vec!["abc", "def"]
+        );
+        let mut token_chars = DEFAULT_TOKEN_CHARS.to_vec();
+        token_chars.push(':');
+        assert_eq!(
+            extract_tokens("abc:def", &token_chars, normalizer.as_ref()),
+            vec!["abc:def"]
+        );
+    }
+
+    #[test]
+    fn extract_emails_in_text() {
+        assert_eq!(
+            extract_emails(
+                "mail to John.Doe@Example.com, or (admin@example.org)",
+                false
+            ),
+            vec!["john.doe@example.com", "admin@example.org"]
+        );
+        assert_eq!(
+            extract_emails("from: user@mail.example.com.", true),
+            vec!["user@mail.example.com", "mail.example.com"]
+        );
+        assert!(extract_emails("user@localhost or @example.com", false).is_empty());
+    }
+
+    #[test]
+    fn extract_emails_after_non_ascii() {
+        assert_eq!(extract_emails("é@example.com", false), Vec::<String>::new());
+        assert_eq!(
+            extract_emails("메일:user@example.com", false),
+            vec!["user@example.com"]
+        );
+        assert_eq!(
+            extract_emails("éuser@example.com", false),
+            vec!["user@example.com"]
+        );
+    }
+}
@@ -1,29 +1,29 @@
+use crate::parser;
 use crate::{ubold, RuleId, TidbId};
-use ansi_term::Style;
 use anyhow::{anyhow, Context, Result};
 use flate2::read::GzDecoder;
 use glob::glob;
-use log::info;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::io::Read;
+use std::path::Path;
+use std::thread;
 use std::{fs::File, io::BufReader};
 
-#[derive(Debug, Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+// extensions of tidb files looked up in a directory. tidb files are gzipped bincode.
+const TIDB_EXTENSIONS: &[&str] = &["aice", "gz"];
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TiKind {
+    #[default]
     Ip,
     Url,
     Token,
     Regex,
 }
 
-impl Default for TiKind {
-    fn default() -> Self {
-        TiKind::Ip
-    }
-}
-
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TiRules {
     rule_id: RuleId,
@@ -59,6 +59,21 @@ impl TiRules {
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    #[must_use]
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    #[must_use]
+    pub fn samples(&self) -> &[String] {
+        self.samples.as_deref().unwrap_or_default()
+    }
+
+    #[must_use]
+    pub fn signatures(&self) -> &[String] {
+        self.signatures.as_deref().unwrap_or_default()
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -77,7 +92,7 @@ impl ComplexRules {
     /// * fail to open file
     /// * invalid json format in file
     pub fn from_aice(path: &str) -> Result<Self> {
-        let file = File::open(&path)?;
+        let file = File::open(path)?;
         let decoder = GzDecoder::new(file);
         let mut buf = Vec::new();
         let mut reader = BufReader::new(decoder);
@@ -94,19 +109,101 @@ impl ComplexRules {
         self.id
     }
 
-    pub fn new(path: &str) -> Result<Vec<Self>> {
+    /// Load tidb files in parallel. Warn if the version of tidb is not one of `versions`.
+    /// All versions are allowed if `versions` is empty.
+    ///
+    /// Return the tidbs sorted by id.
+    pub fn new(path: &str, versions: &[String]) -> Result<Vec<Self>> {
+        let files = files_from(path)?;
+        let loaded: Vec<Result<Self>> = if files.is_empty() {
+            Vec::new()
+        } else {
+            let chunk_size = files.len().div_ceil(num_cpus::get().max(1));
+            thread::scope(|s| {
+                let handles: Vec<_> = files
+                    .chunks(chunk_size)
+                    .map(|chunk| {
+                        s.spawn(move || {
+                            chunk
+                                .iter()
+                                .map(|file| ComplexRules::from_aice(file))
+                                .collect::<Vec<_>>()
+                        })
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .zip(files.chunks(chunk_size))
+                    .flat_map(|(handle, chunk)| {
+                        handle.join().unwrap_or_else(|_| {
+                            chunk
+                                .iter()
+                                .map(|file| Err(anyhow!("failed to load {}", file)))
+                                .collect()
+                        })
+                    })
+                    .collect()
+            })
+        };
+
+        // report in the order of files
         let mut tidbs = Vec::new();
-        for file in files_from(path)? {
+        for (file, result) in files.iter().zip(loaded) {
             info!("loading {}", file);
-            match ComplexRules::from_aice(&file) {
-                Ok(x) => tidbs.push(x),
+            match result {
+                Ok(x) => {
+                    if !x.is_compatible(versions) {
+                        let msg = format!(
+                            "{}: tidb {} {} version {} is not one of {:?}",
+                            file, x.id, x.name, x.version, versions
+                        );
+                        warn!("{}", msg);
+                        eprintln!("Warning: {}", msg);
+                    }
+                    tidbs.push(x);
+                }
                 Err(e) => eprintln!("Error: {}", e),
             }
         }
+        tidbs.sort_by_key(|tidb| tidb.id);
 
         Ok(tidbs)
     }
 
+    #[must_use]
+    pub fn is_compatible(&self, versions: &[String]) -> bool {
+        versions.is_empty() || versions.contains(&self.version)
+    }
+
+    #[must_use]
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    #[must_use]
+    pub fn kind(&self) -> TiKind {
+        self.kind
+    }
+
+    #[must_use]
+    pub fn patterns_count(&self) -> usize {
+        self.patterns.len()
+    }
+
+    /// Return the tokens of the signatures if the rules are token rules.
+    #[must_use]
+    pub fn signature_tokens(&self, token_chars: &[char]) -> Vec<String> {
+        if self.kind != TiKind::Token {
+            return Vec::new();
+        }
+        self.patterns
+            .iter()
+            .filter_map(|p| p.signatures.as_ref())
+            .flatten()
+            .flat_map(|sig| parser::extract_tokens(sig, token_chars, &parser::DefaultNormalizer))
+            .collect()
+    }
+
     pub fn get_label_name(&self, tidb_id: TidbId, rule_id: RuleId) -> Option<&str> {
         if tidb_id == self.id {
             if let Some(x) = self.patterns.iter().find(|p| p.rule_id == rule_id) {
@@ -116,15 +213,45 @@ impl ComplexRules {
         }
         None
     }
+
+    #[must_use]
+    pub fn get_rule(&self, tidb_id: TidbId, rule_id: RuleId) -> Option<&TiRules> {
+        if tidb_id == self.id {
+            self.patterns.iter().find(|p| p.rule_id == rule_id)
+        } else {
+            None
+        }
+    }
+
+    pub fn get_label_description(&self, tidb_id: TidbId, rule_id: RuleId) -> Option<&str> {
+        if tidb_id == self.id {
+            if let Some(x) = self.patterns.iter().find(|p| p.rule_id == rule_id) {
+                return x.description();
+            }
+            return self.description.as_deref();
+        }
+        None
+    }
 }
 
+/// Return the tidb files in the directory `name` and its subdirectories, or the files
+/// matching `name` if it is a glob pattern. Symbolic links to directories are not followed.
+///
 /// # Errors
 ///
 /// Will return `Err` if a path cannot be read to determine if its contents match the glob pattern.
 /// This is possible if the program lacks the appropriate permissions, for example.
 fn files_from(name: &str) -> Result<Vec<String>> {
+    if Path::new(name).is_dir() {
+        let mut files = Vec::new();
+        walk_dir(Path::new(name), &mut files)?;
+        files.sort_unstable();
+        info!("{} tidb files found in {}", files.len(), name);
+        return Ok(files);
+    }
     let mut files: Vec<String> = Vec::new();
-    for p in glob(name).unwrap().filter_map(Result::ok) {
+    let paths = glob(name).with_context(|| format!("invalid tidb path pattern {}", name))?;
+    for p in paths.filter_map(Result::ok) {
         let filepath = p
             .to_str()
             .ok_or_else(|| anyhow!("invalid path"))?
@@ -133,3 +260,59 @@ fn files_from(name: &str) -> Result<Vec<String>> {
     }
     Ok(files)
 }
+
+fn walk_dir(dir: &Path, files: &mut Vec<String>) -> Result<()> {
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("cannot read {}", dir.display()))?;
+    for entry in entries.filter_map(std::result::Result::ok) {
+        let path = entry.path();
+        let file_type = entry
+            .file_type()
+            .with_context(|| format!("cannot read {}", path.display()))?;
+        if file_type.is_dir() {
+            walk_dir(&path, files)?;
+        } else if file_type.is_symlink() && path.is_dir() {
+            // a link may point to one of its ancestors
+            let msg = format!("symbolic link to directory {} is skipped", path.display());
+            warn!("{}", msg);
+            eprintln!("Warning: {}", msg);
+        } else if path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| TIDB_EXTENSIONS.contains(&ext))
+        {
+            let filepath = path.to_str().ok_or_else(|| anyhow!("invalid path"))?;
+            files.push(filepath.to_string());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_from_invalid_glob() {
+        assert!(files_from("[a").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn files_from_dir_with_symlink_loop() {
+        let dir = std::env::temp_dir().join(format!("labeler-{}-tidb-loop", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).expect("writable temp dir");
+        std::fs::write(dir.join("sub").join("a.aice"), "").expect("writable temp dir");
+        let link = dir.join("sub").join("loop");
+        if std::fs::symlink_metadata(&link).is_err() {
+            std::os::unix::fs::symlink(&dir, &link).expect("symlink in temp dir");
+        }
+        let files = files_from(&dir.display().to_string());
+        // the link is removed, not followed
+        std::fs::remove_dir_all(&dir).expect("removable temp dir");
+        assert_eq!(
+            files.expect("readable dir"),
+            vec![dir.join("sub").join("a.aice").display().to_string()]
+        );
+    }
+}